@@ -0,0 +1,21 @@
+use crate::conv;
+use nn::{Sample, Sequential, ActivationFunction, OptimizerFunction, Initializer};
+
+/// Small vision transformer, every 4x4 patch of a digit is a step of the sequence
+fn vit(seed: u64) -> Sequential {
+    Sequential::with_seed(&[28*28], seed)
+        .reshape(&[1, 28, 28])
+        .patch_embedding(4, 32, Initializer::GlorotUniform)
+        .positional(Initializer::GlorotUniform)
+        .encoder(4, 64, ActivationFunction::Gelu)
+        .encoder(4, 64, ActivationFunction::Gelu)
+        .flatten()
+        .dense(10, Initializer::for_activation(ActivationFunction::Softmax))
+        .activation(ActivationFunction::Softmax)
+        .with_optimizer(OptimizerFunction::adam())
+}
+
+/// Trains an attention network without the gui, printing the test accuracy after every epoch
+pub fn attention(training_data: Vec<Sample>, testing_data: &[Sample], seed: Option<u64>) {
+    conv::train(vit(seed.unwrap_or_else(rand::random)), training_data, testing_data, "./saved_attention.json");
+}
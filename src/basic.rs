@@ -19,15 +19,13 @@ fn tekenen_to_sample(image: &Tekenen, value: f32) -> Samples {
 mod nn;
 use std::{time::Instant, process::Command};
 
-use nn::{NN, Sample, Samples};
+use self::nn::{NN, Sample, Samples};
 
 use rand::{seq::SliceRandom, Rng};
 use tekenen::{platform::{Platform, PlatformTrait, IntervalDecision, Event}, Tekenen, colors, ui::*};
 
 use preloaded::load_preloaded;
 
-use image;
-
 static mut ID: i32 = 0;
 
 fn save_frame(nn: &mut Box<NN>, at: f32, path: &str) {
@@ -38,10 +36,7 @@ fn save_frame(nn: &mut Box<NN>, at: f32, path: &str) {
 
     for x in 0..size {
         for y in 0..size {
-            let mut c = nn.get(&vec![x as f32 / size as f32, y as f32 / size as f32, at])[0];
-
-            if c < 0.0 { c = 0.0 }
-            if c > 1.0 { c = 1.0 }
+            let c = nn.get(&vec![x as f32 / size as f32, y as f32 / size as f32, at])[0].clamp(0.0, 1.0);
 
             let c = (c * 255.0) as u8;
 
@@ -56,7 +51,7 @@ fn save_frame(nn: &mut Box<NN>, at: f32, path: &str) {
     println!("Saving image at: {path}");
     let path = std::path::Path::new(&path);
 
-    image::save_buffer(&path, buffer, size as u32, size as u32, image::ColorType::Rgba8).unwrap();
+    image::save_buffer(path, buffer, size as u32, size as u32, image::ColorType::Rgba8).unwrap();
 }
 
 fn save(nn: &mut Box<NN>, at: f32, num: f32) {
@@ -105,22 +100,22 @@ fn save_video(nn: &mut Box<NN>) {
     // ffmpeg -framerate 25 -i './saves/vid_0_%d.png' ./vid_a.mp4
 }
 
-pub fn basic() {
-    let and_data = vec![
+pub fn basic(seed: Option<u64>) {
+    let and_data = [
         Sample { input: vec![0.0, 0.0], output: vec![0.0] },
         Sample { input: vec![0.0, 1.0], output: vec![0.0] },
         Sample { input: vec![1.0, 0.0], output: vec![0.0] },
         Sample { input: vec![1.0, 1.0], output: vec![1.0] },
     ];
 
-    let or_data = vec![
+    let or_data = [
         Sample { input: vec![0.0, 0.0], output: vec![0.0] },
         Sample { input: vec![0.0, 1.0], output: vec![1.0] },
         Sample { input: vec![1.0, 0.0], output: vec![1.0] },
         Sample { input: vec![1.0, 1.0], output: vec![1.0] },
     ];
 
-    let xor_data = vec![
+    let xor_data = [
         Sample { input: vec![0.0, 0.0], output: vec![0.0] },
         Sample { input: vec![0.0, 1.0], output: vec![1.0] },
         Sample { input: vec![1.0, 0.0], output: vec![1.0] },
@@ -128,13 +123,14 @@ pub fn basic() {
     ];
 
     let arch = [3, 27, 27, 9, 1];
-    let mut nn = NN::new(&arch);
+    let new_nn = move || NN::with_seed(&arch, seed.unwrap_or_else(rand::random));
+    let mut nn = new_nn();
 
     let preloaded = load_preloaded();
     let mut training_data = Vec::new();
 
-    for i in 0..=9 {
-        training_data.append(&mut tekenen_to_sample(&preloaded[i], i as f32 - 4.5))
+    for (i, tekenen) in preloaded.iter().enumerate().take(10) {
+        training_data.append(&mut tekenen_to_sample(tekenen, i as f32 - 4.5))
     }
 
     let mut training_iterations = 0;
@@ -177,7 +173,7 @@ pub fn basic() {
                         ' ' => active = !active,
                         'r' => {
                             start = Instant::now();
-                            nn = NN::new(&arch);
+                            nn = new_nn();
                             graph = Vec::new();
                             training_iterations = 0;
                         },
@@ -214,6 +210,7 @@ pub fn basic() {
 
         let running = Instant::now() - start;
 
+        tekenen.draw_text(&format!("Seed: {}", nn.seed()), 450, 400);
         tekenen.draw_text(&format!("Score: {}", score), 450, 425);
         tekenen.draw_text(&format!("Batch size: {}", batch_slider.value as usize), 450, 450);
         tekenen.draw_text(&format!("Iteration: {}", training_iterations), 450, 475);
@@ -223,11 +220,9 @@ pub fn basic() {
         tekenen.draw_text(&format!("Arch: {:?}", arch), 450, 575);
 
 
-        let mut rng = rand::thread_rng();
-
         let batch_size = batch_slider.value as usize;
         while !Platform::get_remaining_time().is_zero() && active {
-            training_data.shuffle(&mut rng);
+            training_data.shuffle(nn.rng());
 
             for i in 0..(training_data.len() / batch_size) - 1 {
                 let start = i * batch_size;
@@ -253,14 +248,11 @@ pub fn basic() {
         let size = 28 * scale;
         for x in 0..size {
             for y in 0..size {
-                let mut c = nn.get(&vec![x as f32 / size as f32, y as f32 / size as f32, rate_slider.value])[0];
-
-                if c < 0.0 { c = 0.0 }
-                if c > 1.0 { c = 1.0 }
+                let c = nn.get(&vec![x as f32 / size as f32, y as f32 / size as f32, rate_slider.value])[0].clamp(0.0, 1.0);
 
                 let c = (c * 255.0) as u8;
 
-                tekenen.set_pixel(x as i32 + scale * 58, y as i32, [c, c, c, 255]);
+                tekenen.set_pixel(x + scale * 58, y, [c, c, c, 255]);
             }
         }
 
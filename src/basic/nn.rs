@@ -1,7 +1,7 @@
-use std::{cell::RefCell, borrow::BorrowMut, ops::{Mul, AddAssign}};
+use std::{cell::RefCell, borrow::BorrowMut};
 
-use rand::Rng;
-use ndarray::{arr2, arr1, Array1, Array2, Array, Axis};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use ndarray::{arr1, Array1, Array2, Array};
 
 #[derive(Debug, Default)]
 pub struct Layer {
@@ -28,19 +28,11 @@ pub struct Sample {
 pub type Samples = Vec<Sample>;
 
 fn new_vec(size: usize) -> Array1<f32> {
-    let mut vec = Vec::with_capacity(size);
-
-    for _ in 0..size  {
-        vec.push(0.0);
-    }
-
-    arr1(&vec)
+    arr1(&vec![0.0; size])
 }
 
 
-fn new_layer(size: usize) -> Layer {
-    let mut rng = rand::thread_rng();
-    
+fn new_layer(size: usize, rng: &mut StdRng) -> Layer {
     let layer = Layer {
         value_a: RefCell::new(new_vec(size)),
         unscaled_z: RefCell::new(new_vec(size)),
@@ -66,9 +58,7 @@ pub struct Connections {
     gradient_w: Array2<f32>,
 }
 
-fn new_connections(current: &Layer, previous: &Layer) -> Connections {
-    let mut rng = rand::thread_rng();
-
+fn new_connections(current: &Layer, previous: &Layer, rng: &mut StdRng) -> Connections {
     let mut value = Array::<f32, _>::zeros((current.len(), previous.len()));
     let gradient = Array::<f32, _>::zeros((current.len(), previous.len()));
 
@@ -88,19 +78,24 @@ fn new_connections(current: &Layer, previous: &Layer) -> Connections {
 #[derive(Debug)]
 pub struct NN {
     pub layers: Vec<Layer>,
-    pub connections: Vec<Connections>
+    pub connections: Vec<Connections>,
+    seed: u64,
+    rng: StdRng
 }
 
 impl NN {
-    pub fn new(arch: &[usize]) -> Box<Self> {
+    /// Two networks with the same seed are initialized and trained identically
+    pub fn with_seed(arch: &[usize], seed: u64) -> Box<Self> {
         assert!(arch.len() > 1, "Invalid arch");
 
+        let mut rng = StdRng::seed_from_u64(seed);
+
 
         // create each layer
         let mut layers = Vec::with_capacity(arch.len());
 
-        for layer in 0..arch.len() {
-            layers.push(new_layer(arch[layer]))
+        for &size in arch.iter() {
+            layers.push(new_layer(size, &mut rng))
         }
 
 
@@ -108,17 +103,28 @@ impl NN {
         let mut connections = Vec::with_capacity(arch.len() - 1);
 
         for connection in 1..arch.len() {
-            connections.push(new_connections(&layers[connection], &layers[connection - 1]))
+            connections.push(new_connections(&layers[connection], &layers[connection - 1], &mut rng))
         }
 
 
         // compose layers and connections
         Box::new(Self {
             layers,
-            connections
+            connections,
+            seed,
+            rng
         })
     }
 
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Rng of the network, use it to shuffle the samples to keep training reproducible
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
     // let out = io[0] as f32 * nn.wa + io[1] as f32 * nn.wb + nn.b;
     // fn forward_old(&mut self, input: &Input) {
 
@@ -421,42 +427,11 @@ impl NN {
     }
 }
 
-enum ActivationFunction {
-    Linear(f32),
-    Sigmoid
-}
-
-// const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Linear(0.0);
-// const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Linear(0.01);
-const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Sigmoid;
-
 fn activation_function(value: f32) -> f32 {
-    match ACTIVATION_FUNCTION {
-        ActivationFunction::Sigmoid => {
-            1.0 / (1.0 + f32::powf(std::f32::consts::E, -value))
-        },
-        ActivationFunction::Linear(a) => {
-            if value > 0.0 {
-                value
-            } else {
-                value * a
-            }
-        }
-    }
+    1.0 / (1.0 + f32::powf(std::f32::consts::E, -value))
 }
 
 fn activation_function_derivate(value: f32) -> f32 {
-    match ACTIVATION_FUNCTION {
-        ActivationFunction::Sigmoid => {
-            let fun = activation_function(value);
-            fun * (1.0 - fun)
-        },
-        ActivationFunction::Linear(a) => {
-            if value > 0.0 {
-                1.0
-            } else {
-                a
-            }
-        }
-    }
-}
\ No newline at end of file
+    let fun = activation_function(value);
+    fun * (1.0 - fun)
+}
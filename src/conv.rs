@@ -0,0 +1,76 @@
+use rand::seq::SliceRandom;
+
+use std::{fs, time::Instant};
+
+use nn::{Sample, Sequential, ActivationFunction, OptimizerFunction, Initializer, Mode, Workspace, batch};
+
+const EPOCHS: usize = 10;
+const BATCH_SIZE: usize = 32;
+const LEARNING_RATE: f32 = 0.001;
+
+/// LeNet-5 like classifier, the digits keep their 28x28 shape
+fn lenet(seed: u64) -> Sequential {
+    let relu = Initializer::for_activation(ActivationFunction::ReLU);
+
+    Sequential::with_seed(&[28*28], seed)
+        .reshape(&[1, 28, 28])
+        .conv2d(6, (5, 5), 1, 2, relu)
+        .activation(ActivationFunction::ReLU)
+        .max_pool(2, 2)
+        .conv2d(16, (5, 5), 1, 0, relu)
+        .activation(ActivationFunction::ReLU)
+        .max_pool(2, 2)
+        .flatten()
+        .dense(120, relu)
+        .activation(ActivationFunction::ReLU)
+        .dense(84, relu)
+        .activation(ActivationFunction::ReLU)
+        .dense(10, Initializer::for_activation(ActivationFunction::Softmax))
+        .activation(ActivationFunction::Softmax)
+        .with_optimizer(OptimizerFunction::adam())
+}
+
+fn accuracy(model: &Sequential, data: &[Sample]) -> f32 {
+    let right = data.iter().filter(|sample| {
+        let out = model.predict(&sample.input);
+
+        crate::heighest(&out) == crate::heighest(&sample.output)
+    }).count();
+
+    right as f32 / data.len() as f32 * 100.0
+}
+
+/// Trains a convolutional network without the gui, printing the test accuracy after every epoch
+pub fn conv(training_data: Vec<Sample>, testing_data: &[Sample], seed: Option<u64>) {
+    train(lenet(seed.unwrap_or_else(rand::random)), training_data, testing_data, "./saved_conv.json");
+}
+
+/// Trains any model of the digits without the gui, printing the test accuracy after every epoch, then saves it to `path`
+pub fn train(mut model: Sequential, mut training_data: Vec<Sample>, testing_data: &[Sample], path: &str) {
+    let mut workspace = Workspace::new(&model);
+
+    println!("Training {} layers on {} samples", model.layers.len(), training_data.len());
+
+    for epoch in 1..=EPOCHS {
+        let start = Instant::now();
+
+        training_data.shuffle(model.rng());
+        model.set_mode(Mode::Train);
+
+        for chunk in training_data.chunks(BATCH_SIZE) {
+            let (inputs, outputs) = batch(chunk);
+
+            if let Err(error) = model.train_batch(&mut workspace, inputs.into_dyn(), &outputs.into_dyn(), LEARNING_RATE) {
+                println!("Stopped training: {error}");
+                return
+            }
+        }
+
+        model.set_mode(Mode::Eval);
+
+        println!("Epoch {epoch}: loss {}, {}% right, took {:?}", model.score(testing_data), accuracy(&model, testing_data), start.elapsed());
+    }
+
+    let data = serde_json::to_string(&model).unwrap();
+    fs::write(path, data).unwrap();
+}
@@ -0,0 +1,3 @@
+// the gui in main and its demos use the networks like any other crate
+mod nn;
+pub use nn::*;
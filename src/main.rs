@@ -1,38 +1,42 @@
 #![allow(unused_variables)]
 
 mod basic;
+// generated by the preloader in build.rs
+#[allow(non_camel_case_types)]
 mod preloaded;
+mod conv;
+mod attention;
 
 use rand::seq::SliceRandom;
 use tekenen::*;
 use platform::*;
 
-mod nn;
-use nn::{Input, Sample};
+use nn::{Input, Sample, ActivationFunction, OptimizerFunction, LayerConfig, Regularization, Clipping, FakeQuantization, Sparsity, NNError, Workspace, Mode, batch};
+use nn::quantize::Granularity;
 
 use std::{time::Instant};
 
-use crate::nn::Output;
+use nn::Output;
 
 use image::GenericImageView;
 
-use serde_json;
 use std::fs;
 
+// training samples the ranges of the int8 values are calibrated on
+const QUANTIZATION_CALIBRATION: usize = 1000;
+
+// every press of <p> prunes one more round, training in between fine-tunes what is left
+const PRUNING_TARGET: f32 = 0.9;
+const PRUNING_ROUNDS: usize = 5;
+// sparsities the accuracy and speed of one-shot pruning are reported at with --sparsities
+const PRUNING_SERIES: [f32; 6] = [0.0, 0.5, 0.7, 0.8, 0.9, 0.95];
+
 fn load_data(data: &Tekenen, number: usize) -> Sample {
     let size = data.width() * data.height();
 
-    let layer = Input::new(size);
     let pixels = data.get_pixels();
 
-    {
-        let mut bias = layer.bias_b.borrow_mut();
-        let mut value = layer.value_a.borrow_mut();
-        for i in 0..size {
-            bias[i] = 0.0;
-            value[i] = pixels[i * 4] as f32 / 255.0;
-        };
-    }
+    let layer = Input::from_shape_fn(size, |i| pixels[i * 4] as f32 / 255.0);
 
     let mut output = Vec::new();
 
@@ -47,13 +51,13 @@ fn load_data(data: &Tekenen, number: usize) -> Sample {
     Sample { input: layer, output }
 }
 
-fn heighest(out: &Vec<f32>) -> usize {
+fn heighest(out: &[f32]) -> usize {
     let mut record = -1.0;
     let mut holder = 0;
 
-    for i in 0..=9 {
-        if out[i] > record {
-            record = out[i];
+    for (i, &value) in out.iter().enumerate().take(10) {
+        if value > record {
+            record = value;
             holder = i;
         }
     }
@@ -61,7 +65,7 @@ fn heighest(out: &Vec<f32>) -> usize {
     holder
 }
 
-fn score_all(nn: &mut Box<nn::NN>, data: &Vec<Sample>) -> String {
+fn score_all(nn: &nn::NN, data: &[Sample]) -> String {
     let mut right = 0;
 
     for data in data.iter() {
@@ -118,24 +122,43 @@ fn load_set(path: &str) -> Vec<Sample> {
     samples
 }
 
+/// Number following one of the names in the arguments
+fn arg_value(args: &[String], names: &[&str]) -> Option<u64> {
+    args.iter().position(|el: &String| { names.contains(&el.as_str()) }).map(|i| {
+        args.get(i + 1).and_then(|value| value.parse::<u64>().ok()).unwrap_or_else(|| panic!("Expected a number after {}", args[i]))
+    })
+}
+
 fn main () {
     let args: Vec<String> = std::env::args().collect();
 
     let basic = args.iter().any(|el: &String| { ["-b", "-basic", "--basic"].contains(&el.as_str()) });
     let preload = args.iter().any(|el: &String| { ["-p", "-preload", "--preload", "-preloaded", "--preloaded"].contains(&el.as_str()) });
+    let conv = args.iter().any(|el: &String| { ["-c", "-conv", "--conv"].contains(&el.as_str()) });
+    let attention = args.iter().any(|el: &String| { ["-a", "-attention", "--attention"].contains(&el.as_str()) });
+    let sparsities = args.iter().any(|el: &String| { ["-z", "-sparsities", "--sparsities"].contains(&el.as_str()) });
     let help = args.iter().any(|el: &String| { ["-h", "-help", "--help"].contains(&el.as_str()) });
+    let seed = arg_value(&args, &["-s", "-seed", "--seed"]);
+    let threads = arg_value(&args, &["-t", "-threads", "--threads"]).unwrap_or(1) as usize;
+    let quantization = arg_value(&args, &["-q", "-quantize", "--quantize"]).map(|bits| FakeQuantization::new(bits as u32, Granularity::Channel));
 
     if help {
         println!("Usage of nn:
         
         <-h, --help>    Show this message
         <-b, --basic>   Load basic variant, memorize the numbers 1-10
-        <-p, --preload> Use prelaoded data baked into the binary");
+        <-p, --preload> Use prelaoded data baked into the binary
+        <-c, --conv>    Train a convolutional network without the gui
+        <-a, --attention> Train an attention network on patches of the digits without the gui
+        <-z, --sparsities> Report the accuracy and speed of ./saved_nn.json pruned to a series of sparsities, without the gui
+        <-s, --seed> n  Seed the network and the shuffling, to reproduce a run
+        <-t, --threads> n Split each batch across n threads
+        <-q, --quantize> n Train for a deployment with n bits, 8 or 4");
         return;
     }
 
     if basic {
-        basic::basic();
+        basic::basic(seed);
         return;
     }
 
@@ -166,13 +189,59 @@ fn main () {
         (training, testing)
     };
 
+    if conv {
+        conv::conv(training_data, &testing_data, seed);
+        return;
+    }
+
+    if attention {
+        attention::attention(training_data, &testing_data, seed);
+        return;
+    }
+
+    if sparsities {
+        let loaded = fs::read_to_string("./saved_nn.json").map_err(|error| error.to_string())
+            .and_then(|data| nn::NN::from_json(&data).map_err(|error| error.to_string()));
+
+        match loaded {
+            Ok(nn) => nn.pruning_series(&PRUNING_SERIES, &testing_data).iter().for_each(|report| println!("{report}")),
+            Err(error) => println!("Can't load ./saved_nn.json: {error}")
+        }
+
+        return;
+    }
+
     let mut window = Platform::new(800, 600).unwrap();
     let mut tekenen = Tekenen::new(800, 600);
 
     let mut showing_i = 0;
 
     let arch = [28*28, 32, 16, 10];
-    let mut nn = nn::NN::new(&arch);
+    let activations = [ActivationFunction::Sigmoid, ActivationFunction::Sigmoid, ActivationFunction::Softmax];
+    let dropouts = [0.1, 0.0, 0.0];
+    let configs: Vec<LayerConfig> = arch[1..].iter().zip(activations).zip(dropouts).map(|((size, activation), dropout)| {
+        LayerConfig::new(*size, activation).dropout(dropout)
+    }).collect();
+    let new_nn = move || {
+        let seed = seed.unwrap_or_else(rand::random);
+        let nn = nn::NN::from_seed(arch[0], &configs, seed)
+            .with_optimizer(OptimizerFunction::adam())
+            .with_regularization(Regularization::l2(1e-4))
+            .with_clipping(Clipping::norm(5.0));
+
+        let mut nn = match quantization {
+            Some(quantization) => nn.with_quantization(quantization),
+            None => nn
+        };
+
+        // scored, saved and quantized the way it is deployed, only training switches to Mode::Train
+        nn.set_mode(Mode::Eval);
+        nn
+    };
+    let mut nn = new_nn();
+
+    // one per thread, kept from batch to batch
+    let mut workspaces = vec![Workspace::new(&nn); threads.max(1)];
 
     let mut parameters = 0;
     for i in 1..arch.len() {
@@ -186,11 +255,12 @@ fn main () {
 
     let mut batch_slider = ui::widgets::Slider::new_sized(0, 200, 50, 1.0, 200.0, 20.0);
     let mut testing = 0;
-    let mut learning_rate = 0.1;
+    let mut learning_rate = 0.001;
 
     let mut graph_data = Vec::new();
 
     let mut correct = "Press <c> to update".to_owned();
+    let mut diverged: Option<NNError> = None;
 
     let mut showing_map = false;
     let mut drawing = false;
@@ -200,6 +270,7 @@ fn main () {
     let mut drawing_sample = load_data(&drawing_sample_canvas, 0);
 
     let mut shuffled_until = usize::MAX;
+    let mut pruning_round = 0;
 
     for i in 0..=9 {
         drawing_sample.output[i] = i as f32;
@@ -214,21 +285,59 @@ fn main () {
                 },
                 Event::KeyDown { char: Some(char), .. } => {
                     match char {
-                        ' ' => running = !running,
+                        ' ' => {
+                            running = !running;
+                            diverged = None;
+                        },
                         'i' => learning_rate *= 2.0,
                         'o' => learning_rate /= 2.0,
                         'n' => testing += testing_data.len() / 10 + 1,
                         'm' => testing -= testing_data.len() / 10 + 1,
-                        'c' => correct = score_all(&mut nn, &testing_data),
+                        'c' => correct = score_all(&nn, &testing_data),
                         's' => showing_map = !showing_map,
                         'l' => {
-                            let data = fs::read_to_string("./saved_nn.json").unwrap();
-                            nn = serde_json::from_str(&data).unwrap();
+                            // a missing file or one saved by an older version keeps the current network
+                            let loaded = fs::read_to_string("./saved_nn.json").map_err(|error| error.to_string())
+                                .and_then(|data| nn::NN::from_json(&data).map_err(|error| error.to_string()));
+
+                            match loaded {
+                                Ok(loaded) => {
+                                    nn = loaded;
+                                    workspaces = vec![Workspace::new(&nn); threads.max(1)];
+                                },
+                                Err(error) => println!("Can't load ./saved_nn.json: {error}")
+                            }
                         },
                         'k' => {
                             let data = serde_json::to_string(&nn).unwrap();
                             fs::write("./saved_nn.json", data).unwrap();
                         },
+                        'q' => {
+                            // a network trained with fake quantization already knows its ranges, any other is calibrated
+                            let quantized = if let Some(quantized) = nn.export_quantized() {
+                                quantized
+                            } else {
+                                let calibration = &training_data[..training_data.len().min(QUANTIZATION_CALIBRATION)];
+
+                                println!("{}", nn.quantize_int8(calibration, Granularity::Layer).report(&nn, &testing_data));
+
+                                // per channel scales lose less accuracy, that one is saved
+                                nn.quantize_int8(calibration, Granularity::Channel)
+                            };
+
+                            correct = quantized.report(&nn, &testing_data).to_string();
+                            println!("{correct}");
+
+                            let data = serde_json::to_string(&quantized).unwrap();
+                            fs::write("./saved_nn_quantized.json", data).unwrap();
+                        },
+                        'p' => {
+                            pruning_round = (pruning_round + 1).min(PRUNING_ROUNDS);
+                            nn.prune(&Sparsity::Global(PRUNING_TARGET).gradual(pruning_round, PRUNING_ROUNDS));
+
+                            correct = nn.sparse().report(&nn, &testing_data).to_string();
+                            println!("{correct}");
+                        },
                         'd' => {
                             drawing = !drawing;
                             drawing_canvas.background([0, 0, 0, 255]);
@@ -236,8 +345,11 @@ fn main () {
                         'r' => {
                             started = Instant::now();
                             training_iterations = 0;
-                            nn = nn::NN::new(&arch);
+                            nn = new_nn();
+                            workspaces = vec![Workspace::new(&nn); threads.max(1)];
+                            pruning_round = 0;
                             graph_data = Vec::new();
+                            diverged = None;
                         },
                         _ => { }
                     }
@@ -250,7 +362,7 @@ fn main () {
                 },
                 Event::MouseMove { x, y } => {
                     batch_slider.mouse_move(x, y);
-                    if let Some(_) = mouse_down {
+                    if mouse_down.is_some() {
                         mouse_down = Some((x, y));
                     }
                 },
@@ -263,21 +375,28 @@ fn main () {
         }
 
         // Train AI
-        let mut rng = rand::thread_rng();
         let running_time = Instant::now() - started;
 
         let batch_size = batch_slider.value as usize;
+        nn.set_mode(Mode::Train);
         'out: while !Platform::get_remaining_time().is_zero() && running {
             if shuffled_until >= training_data.len() - batch_size {
                 shuffled_until = 0;
-                training_data.shuffle(&mut rng);
+                training_data.shuffle(nn.rng());
             }
 
             while shuffled_until + batch_size < training_data.len() {
                 let start = shuffled_until;
                 let end = start + batch_size;
+                let (inputs, outputs) = batch(&training_data[start..end]);
     
-                nn.train_samples(&training_data[start..end], learning_rate);
+                // stop before the network is corrupted, the rate is probably too high
+                if let Err(error) = nn.train_batch_parallel(&mut workspaces, &inputs, &outputs, learning_rate) {
+                    println!("{error}");
+                    diverged = Some(error);
+                    running = false;
+                    break 'out
+                }
 
                 training_iterations += batch_size;
 
@@ -287,6 +406,8 @@ fn main () {
             }
         }
 
+        nn.set_mode(Mode::Eval);
+
         // Draw
         tekenen.background(colors::GRAY);
         batch_slider.display(&mut tekenen);
@@ -297,12 +418,24 @@ fn main () {
 
         let infos = [
             format!("Score: {score}"),
+            format!("Loss: {:?}", nn.loss),
             format!("Test: {correct}"),
             format!("Learning rate: {learning_rate}"),
+            match diverged {
+                Some(error) => format!("Paused: {error}"),
+                None => format!("Clipping: {:?}", nn.clipping)
+            },
+            format!("Optimizer: {:?}", nn.optimizer()),
+            format!("Regularization: {:?}", nn.regularization),
+            format!("Quantization: {:?}", nn.quantization()),
+            format!("Sparsity: {}%", nn.sparsity() * 100.0),
             format!("Batch size: {batch_size}"),
+            format!("Threads: {threads}"),
             format!("Iteration: {training_iterations}"),
             format!("Elapsed: {}", running_time.as_secs()),
             format!("Arch: {:?}", arch),
+            format!("Dropout: {:?}", dropouts),
+            format!("Seed: {}", nn.seed()),
             format!("Training size: {:?}", training_data.len()),
             format!("Testing size: {:?}", testing_data.len()),
             format!("Paramters: {parameters}"),
@@ -317,6 +450,8 @@ fn main () {
             "<s>: Show neuron map".to_string(),
             "<l>: Load AI".to_string(),
             "<k>: Save AI".to_string(),
+            "<q>: Quantize AI".to_string(),
+            "<p>: Prune AI".to_string(),
         ];
 
         for (i, info) in infos.iter().enumerate() {
@@ -325,7 +460,7 @@ fn main () {
 
         // Draw top images
         for i in 0..preloaded.testing.len() {
-            let image = &preloaded.testing[i][showing_i % &preloaded.testing[i].len()];
+            let image = &preloaded.testing[i][showing_i % preloaded.testing[i].len()];
             tekenen.draw_image(29 * i as i32, 0, image)
         }
         showing_i += 1;
@@ -362,17 +497,13 @@ fn main () {
 
             tekenen.draw_image(x1, y1, &drawing_canvas);
         } else if showing_map {
-            let index_1 = testing % nn.layers[1].value_a.borrow().len();
-            let connections = &nn.connections[0];
+            let dense = nn.dense(0);
+            let index_1 = testing % dense.bias_b.len();
 
             for x in 0..28i32 {
                 for y in 0..28i32 {
                     let index_2 = y * 28 + x;
-                    let mut c = connections.value_w[(index_1, index_2 as usize)];
-
-                    c = c / 2.0 + 0.5;
-                    if c < 0.0 { c = 0.0 }
-                    if c > 1.0 { c = 1.0 }
+                    let c = (dense.value_w[(index_1, index_2 as usize)] / 2.0 + 0.5).clamp(0.0, 1.0);
 
                     let c = (c * 255.0) as u8;
                     let x = x1 + x * scale;
@@ -411,7 +542,7 @@ fn main () {
         };
 
 
-        let img_data = testing_img.input.value_a.borrow();
+        let img_data = &testing_img.input;
         let result = nn.get(&testing_img.input);
 
         let x1 = 400;
@@ -423,9 +554,7 @@ fn main () {
                 let x = x1 + dx;
                 let y = y1 + dy;
 
-                let mut c = img_data[i];
-                if c < 0.0 { c = 0.0 }
-                if c > 1.0 { c = 1.0 }
+                let c = img_data[i].clamp(0.0, 1.0);
 
                 let c = (c * 255.0) as u8;
 
@@ -436,11 +565,11 @@ fn main () {
         let mut dispay = |out: &Output, x: i32, y: i32| {
             let holder = heighest(out);
             
-            for i in 0..=9 {
+            for (i, value) in out.iter().enumerate().take(10) {
                 if i == holder {
-                    tekenen.draw_text(&format!("[{}]", out[i]), x, y + i as i32 * 25);
+                    tekenen.draw_text(&format!("[{value}]"), x, y + i as i32 * 25);
                 } else {
-                    tekenen.draw_text(&format!(" {}", out[i]), x, y + i as i32 * 25);
+                    tekenen.draw_text(&format!(" {value}"), x, y + i as i32 * 25);
                 }
             }
         };
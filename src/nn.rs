@@ -1,375 +1,452 @@
-use std::{cell::RefCell, borrow::BorrowMut};
 use serde::{Serialize, Deserialize};
 
-use rand::Rng;
-use ndarray::{arr1, Array1, Array2, Array};
+use std::ops::{Deref, DerefMut};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct Layer {
-    pub value_a: RefCell<Array1<f32>>,
-    unscaled_z: RefCell<Array1<f32>>,
-    error_z: RefCell<Array1<f32>>,
-    pub bias_b: RefCell<Array1<f32>>,
-    gradient_b: RefCell<Array1<f32>>
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+
+use sequential::{LayerKind, Dense, Quantize};
+
+mod float;
+pub use float::Float;
+
+mod activation;
+pub use activation::ActivationFunction;
+
+mod loss;
+pub use loss::{Loss, LossFunction};
+
+mod optimizer;
+pub use optimizer::{Optimizer, OptimizerFunction, OptimizerState};
+
+mod init;
+pub use init::Initializer;
+
+mod regularization;
+pub use regularization::Regularization;
+
+mod normalization;
+pub use normalization::{Normalization, Norm, BatchNorm, LayerNorm};
+
+mod clipping;
+pub use clipping::Clipping;
+
+mod error;
+pub use error::NNError;
+
+mod gradient_check;
+pub use gradient_check::{gradient_check, GradientError};
+
+mod dropout;
+pub use dropout::Mode;
+
+/// Composable models, layers stacked freely, a `NN` is one of them
+pub mod sequential;
+pub use sequential::Sequential;
+
+/// Reverse mode automatic differentiation, gradients of anything recorded on a tape
+pub mod autodiff;
+pub use autodiff::Tape;
+
+/// Models whose layers form any directed acyclic graph, with skip connections and merges
+pub mod graph;
+pub use graph::Graph;
+
+/// Int8 post-training quantization of a trained `NN`, for smaller and integer inference
+pub mod quantize;
+pub use quantize::{QuantizedNN, FakeQuantization};
+
+/// Magnitude pruning of any model, and sparse inference of a `NN` with the weights that are left
+pub mod prune;
+pub use prune::{Sparsity, SparseNN};
+
+mod workspace;
+pub use workspace::Workspace;
+
+// networks saved in the layout from before the layers were configurable
+mod legacy;
+
+pub type Input<F = f32> = Array1<F>;
+pub type Output<F = f32> = Vec<F>;
+pub struct Sample<F = f32> {
+    pub input: Input<F>,
+    pub output: Output<F>,
 }
 
-impl Layer {
-    pub fn new(size: usize) -> Self {
-        let mut rng = rand::thread_rng();
-        
-        let layer = Self {
-            value_a: RefCell::new(new_vec(size)),
-            unscaled_z: RefCell::new(new_vec(size)),
-            error_z: RefCell::new(new_vec(size)),
-            bias_b: RefCell::new(new_vec(size)),
-            gradient_b: RefCell::new(new_vec(size)),
-        };
-    
-        // Init each neuron with a random bias
-        let mut bias = layer.bias_b.borrow_mut();
-        for i in 0..size  {
-            bias[i] = rng.gen_range(-1.0..1.0);
+/// Every layer except the input one gets its own activation function,
+/// so `activations` must be one shorter than `arch`.
+fn layer_configs(arch: &[usize], activations: &[ActivationFunction]) -> Vec<LayerConfig> {
+    assert!(arch.len() > 1, "Invalid arch");
+    assert_eq!(arch.len() - 1, activations.len(), "Invalid activations, one per non input layer");
+
+    arch[1..].iter().zip(activations.iter()).map(|(size, activation)| {
+        LayerConfig::new(*size, *activation)
+    }).collect()
+}
+
+/// Describes a non input layer of the network
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerConfig {
+    pub size: usize,
+    pub activation: ActivationFunction,
+    /// Initializer of the connections coming from the previous layer
+    pub weights: Initializer,
+    pub bias: Initializer,
+    /// Only hidden layers can drop out
+    pub dropout: f32,
+    pub norm: Normalization
+}
+
+impl LayerConfig {
+    /// Weights are initialized depending on the activation, biases start at 0
+    pub fn new(size: usize, activation: ActivationFunction) -> Self {
+        Self {
+            size,
+            activation,
+            weights: Initializer::for_activation(activation),
+            bias: Initializer::Zeros,
+            dropout: 0.0,
+            norm: Normalization::None
         }
-    
-        drop(bias);
-    
-        layer
     }
 
-    pub fn len(&self) -> usize {
-        self.value_a.borrow().len()
+    pub fn weights(mut self, weights: Initializer) -> Self {
+        self.weights = weights;
+        self
     }
-}
 
-pub type Input = Layer;
-pub type Output = Vec<f32>;
-pub struct Sample {
-    pub input: Input,
-    pub output: Output,
-}
+    pub fn bias(mut self, bias: Initializer) -> Self {
+        self.bias = bias;
+        self
+    }
 
-fn new_vec(size: usize) -> Array1<f32> {
-    let mut vec = Vec::with_capacity(size);
+    pub fn dropout(mut self, rate: f32) -> Self {
+        assert!((0.0..1.0).contains(&rate), "Invalid dropout rate {rate}, should be in 0..1");
 
-    for _ in 0..size  {
-        vec.push(0.0);
+        self.dropout = rate;
+        self
     }
 
-    arr1(&vec)
+    pub fn norm(mut self, norm: Normalization) -> Self {
+        self.norm = norm;
+        self
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Connections {
-    pub value_w: Array2<f32>,
-    gradient_w: Array2<f32>,
+/// Multilayer perceptron computing in `f32`, or in any other `Float` like `NN<f64>`.
+/// Its blocks are layers of a `Sequential` model: a dense layer, its normalization, its activation and its dropout,
+/// everything else comes from the model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NN<F: Float = f32> {
+    model: Sequential<F>
 }
 
-fn new_connections(current: &Layer, previous: &Layer) -> Connections {
-    let mut rng = rand::thread_rng();
+impl<F: Float> Deref for NN<F> {
+    type Target = Sequential<F>;
 
-    let mut value = Array::<f32, _>::zeros((current.len(), previous.len()));
-    let gradient = Array::<f32, _>::zeros((current.len(), previous.len()));
-
-    // Init each connection with a random weight
-    for i in 0..current.len() {
-        for j in 0..previous.len() {   
-            value[(i, j)] = rng.gen_range(-1.0..1.0);                
-        }
+    fn deref(&self) -> &Sequential<F> {
+        &self.model
     }
+}
 
-    Connections {
-        value_w: value,
-        gradient_w: gradient
+impl<F: Float> DerefMut for NN<F> {
+    fn deref_mut(&mut self) -> &mut Sequential<F> {
+        &mut self.model
     }
 }
 
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct NN {
-    pub layers: Vec<Layer>,
-    pub connections: Vec<Connections>
+/// The layers of the model making one layer of a `NN`
+#[derive(Debug, Clone, Copy)]
+pub struct Block<'a, F: Float = f32> {
+    /// Rounds the values the dense layer is fed
+    pub quantize: &'a Quantize<F>,
+    pub dense: &'a Dense<F>,
+    pub norm: Option<&'a Norm<F>>,
+    pub activation: ActivationFunction,
+    pub dropout: f32
 }
 
-impl NN {
+impl<F: Float> NN<F> {
     pub fn new(arch: &[usize]) -> Box<Self> {
-        assert!(arch.len() > 1, "Invalid arch");
-
-
-        // create each layer
-        let mut layers = Vec::with_capacity(arch.len());
+        Self::with_seed(arch, rand::random())
+    }
 
-        for layer in 0..arch.len() {
-            layers.push(Layer::new(arch[layer]))
-        }
+    /// Two networks with the same seed are initialized and trained identically
+    pub fn with_seed(arch: &[usize], seed: u64) -> Box<Self> {
+        let activations = vec![ActivationFunction::default(); arch.len().saturating_sub(1)];
 
+        Self::from_seed(arch[0], &layer_configs(arch, &activations), seed)
+    }
 
-        // create each connection, connections are the ammount of layers - 1
-        let mut connections = Vec::with_capacity(arch.len() - 1);
+    pub fn with_activations(arch: &[usize], activations: &[ActivationFunction]) -> Box<Self> {
+        Self::from_config(arch[0], &layer_configs(arch, activations))
+    }
 
-        for connection in 1..arch.len() {
-            connections.push(new_connections(&layers[connection], &layers[connection - 1]))
-        }
+    pub fn from_config(inputs: usize, configs: &[LayerConfig]) -> Box<Self> {
+        Self::from_seed(inputs, configs, rand::random())
+    }
 
+    pub fn from_seed(inputs: usize, configs: &[LayerConfig], seed: u64) -> Box<Self> {
+        assert!(!configs.is_empty(), "Invalid arch");
+        assert_eq!(configs[configs.len() - 1].dropout, 0.0, "The output layer can't drop out");
 
-        // compose layers and connections
-        Box::new(Self {
-            layers,
-            connections
-        })
-    }
+        // the inputs are rounded like the activated values of every hidden layer
+        let mut model = Sequential::with_seed(&[inputs], seed).quantize();
+        let mut fan_in = inputs;
 
-    fn forward(&mut self, input: &Input) {
+        for (i, config) in configs.iter().enumerate() {
+            let dense = Dense::new(fan_in, config.size, config.weights, config.bias, model.rng());
 
-        let mut layer0_value_a = self.layers[0].value_a.borrow_mut();
+            // a softmax output switches the loss to cross entropy
+            model = model.push(LayerKind::Dense(dense)).norm(config.norm).activation(config.activation);
 
-        // layer 0 is input
-        assert_eq!(input.len(), layer0_value_a.len(), "Input layers not of same size!");
+            if i < configs.len() - 1 {
+                model = model.quantize();
+            }
 
-        // could probably swap layer 1 for input layer?
+            if config.dropout > 0.0 {
+                model = model.dropout(config.dropout);
+            }
 
-        let input = input.value_a.borrow();
-        for i in 0..input.len() {
-            layer0_value_a[i] = input[i]
+            fan_in = config.size;
         }
 
-        drop(layer0_value_a);
+        Box::new(Self { model })
+    }
 
-        // calculate value for each successive layer
-        for curr_layer_i in 1..self.layers.len() {
+    pub fn with_optimizer(mut self: Box<Self>, optimizer: OptimizerFunction) -> Box<Self> {
+        self.set_optimizer(optimizer);
+        self
+    }
 
-            let connection = &self.connections[curr_layer_i - 1];
+    pub fn with_regularization(mut self: Box<Self>, regularization: Regularization) -> Box<Self> {
+        self.regularization = regularization;
+        self
+    }
 
-            let curr_layer = &self.layers[curr_layer_i];
-            let prev_layer = &self.layers[curr_layer_i - 1];
+    pub fn with_clipping(mut self: Box<Self>, clipping: Clipping) -> Box<Self> {
+        self.clipping = clipping;
+        self
+    }
 
-            let prev_value_a = prev_layer.value_a.borrow();
-            let curr_bias_b = curr_layer.bias_b.borrow();
+    pub fn with_quantization(mut self: Box<Self>, quantization: FakeQuantization) -> Box<Self> {
+        self.set_quantization(Some(quantization));
+        self
+    }
 
-            let mut curr_unscaled_z = curr_layer.unscaled_z.borrow_mut();
-            // TODO: assign instead of add
+    /// Every layer of the network but the input one, as layers of the model
+    pub fn blocks(&self) -> Vec<Block<'_, F>> {
+        let mut blocks: Vec<Block<'_, F>> = Vec::new();
+        let mut quantize = None;
 
-            *curr_unscaled_z = prev_value_a.dot(&connection.value_w.t());
-            *curr_unscaled_z += &*curr_bias_b;
+        for layer in self.layers.iter() {
+            match layer {
+                LayerKind::Quantize(layer) => quantize = Some(layer),
+                LayerKind::Dense(dense) => blocks.push(Block {
+                    quantize: quantize.expect("Quantize before every dense layer"),
+                    dense,
+                    norm: None,
+                    activation: ActivationFunction::Identity,
+                    dropout: 0.0
+                }),
+                LayerKind::Norm(norm) => blocks.last_mut().expect("Dense layer first").norm = Some(norm),
+                LayerKind::Activation(activation) => blocks.last_mut().expect("Dense layer first").activation = *activation,
+                LayerKind::Dropout(dropout) => blocks.last_mut().expect("Dense layer first").dropout = dropout.rate,
+                layer => panic!("Not a layer of a NN: {layer:?}")
+            }
+        }
 
-            let activated = curr_unscaled_z.map(|unscaled| -> f32 {
-                activation_function(*unscaled)
-            });
+        blocks
+    }
 
-            let mut curr_value_a = curr_layer.value_a.borrow_mut();
-            *curr_value_a = activated;
-            //self.layers[curr_layer_i].unscaled_z.assign(&(self.layers[curr_layer_i].bias_b.add(&self.layers[curr_layer_i - 1].value_a.dot(&connection.value_w))))
-        }
+    /// Dense layer of block `i`, the first one is fed the inputs
+    pub fn dense(&self, i: usize) -> &Dense<F> {
+        self.blocks()[i].dense
     }
 
-    pub fn get(&mut self, input: &Input) -> Output {
-        self.forward(input);
+    /// Only reads the network, so it can be shared between threads.
+    /// Always evaluates, nothing is dropped out whatever the mode.
+    pub fn predict(&self, input: &Input<F>) -> Output<F> {
+        self.model.predict(input)
+    }
 
-        let layer = &self.layers[self.layers.len() -1];
+    /// Output of every input, one sample per row
+    pub fn predict_batch(&self, inputs: &Array2<F>) -> Array2<F> {
+        self.model.predict_batch(inputs.clone().into_dyn()).into_dimensionality().expect("Flat outputs")
+    }
 
-        let mut out = Vec::with_capacity(layer.len());
+    /// Same as `predict`
+    pub fn get(&self, input: &Input<F>) -> Output<F> {
+        self.predict(input)
+    }
 
-        for value in layer.value_a.borrow().iter() {
-            out.push(*value)
-        };
+    /// Trains on a whole mini-batch at once, one sample per row. An empty batch changes nothing.
+    pub fn train_batch(&mut self, workspace: &mut Workspace<F>, inputs: &Array2<F>, outputs: &Array2<F>, rate: F) -> Result<(), NNError> {
+        self.model.train_batch(workspace, inputs.clone().into_dyn(), &outputs.view().into_dyn().to_owned(), rate)
+    }
 
-        out
+    /// Splits the mini-batch in one shard per workspace like `Graph::train_batch_parallel`
+    pub fn train_batch_parallel(&mut self, workspaces: &mut [Workspace<F>], inputs: &Array2<F>, outputs: &Array2<F>, rate: F) -> Result<(), NNError> {
+        assert_eq!(inputs.nrows(), outputs.nrows(), "Not as many inputs as outputs!");
+
+        self.model.train_batch_parallel(workspaces, inputs.clone().into_dyn(), &outputs.view().into_dyn().to_owned(), rate)
     }
+}
 
-    fn backpropagete(&mut self, output: &Output) {
-        let layers_len = self.layers.len();
-
-        {
-            // set last layer error
-            let last_layer = &mut self.layers[layers_len - 1].borrow_mut();
-
-            let mut last_layer_error_z = last_layer.error_z.borrow_mut();
-            let last_layer_value_a = last_layer.value_a.borrow();
-            let last_layer_unscaled_z = last_layer.unscaled_z.borrow();
-
-            last_layer_error_z.iter_mut()
-                .zip(last_layer_value_a.iter())
-                .zip(output.iter())
-                .zip(last_layer_unscaled_z.iter())
-                .for_each(|(((error, value), output), unscaled)| {
-                    *error = (value - output) * activation_function_derivate(*unscaled)
-                });
-
-            // for i in 0..last_layer.len() {
-            //     // TODO: Why nuron.value_a - output[i] and not vice versa?
-            //     last_layer_error_z[i] =  (last_layer_value_a[i] - output[i]) * activate_sigmoid_derivate(last_layer_unscaled_z[i])
-            // }
-        }
+// scoring is done in batches, big enough to be fast but without a copy of the whole set
+const SCORE_BATCH: usize = 256;
 
+/// Stacks the inputs and the outputs of the samples into matrices, one sample per row
+pub fn batch<F: Float>(samples: &[Sample<F>]) -> (Array2<F>, Array2<F>) {
+    assert!(!samples.is_empty(), "Empty batch");
 
-        // loop for each previous layer
-        for curr_layer_i in (1..layers_len).rev() {
-            let curr_layer = &self.layers[curr_layer_i];
-            let prev_layer = &self.layers[curr_layer_i - 1];
-            let connection = &mut self.connections[curr_layer_i - 1].borrow_mut();
+    let inputs: Vec<ArrayView1<F>> = samples.iter().map(|sample| sample.input.view()).collect();
+    let outputs: Vec<ArrayView1<F>> = samples.iter().map(|sample| ArrayView1::from(&sample.output)).collect();
 
-            let curr_error_z = curr_layer.error_z.borrow();
-            let mut prev_error_z = prev_layer.error_z.borrow_mut();
-            let mut curr_gradient_b = curr_layer.gradient_b.borrow_mut();
-            let prev_value_a = prev_layer.value_a.borrow();
-            let prev_unscaled_z = prev_layer.unscaled_z.borrow();
+    (
+        ndarray::stack(Axis(0), &inputs).expect("Input layers not of same size!"),
+        ndarray::stack(Axis(0), &outputs).expect("Output layers not of same size!")
+    )
+}
 
-            for curr_node_i in 0..curr_layer.len() {
-                for prev_node_i in 0..prev_layer.len() {
-                    // update connetions
-                    connection.gradient_w[(curr_node_i, prev_node_i)] += curr_error_z[curr_node_i] * prev_value_a[prev_node_i];
-                } 
-            }
+/// Samples of 3 inputs and 2 outputs for the tests of training, shared by every module
+#[cfg(test)]
+pub(crate) fn samples<F: Float>() -> Vec<Sample<F>> {
+    (0..32).map(|i| {
+        let x = F::of(i as f64 / 32.0);
+
+        Sample { input: Array1::from(vec![x, F::one() - x, x * x]), output: vec![x, F::one() - x] }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::quantize::Granularity;
+
+    #[test]
+    fn trains_every_stage_of_its_layers() {
+        let configs = [
+            LayerConfig::new(8, ActivationFunction::Tanh).norm(Normalization::batch()).dropout(0.2),
+            LayerConfig::new(2, ActivationFunction::Sigmoid)
+        ];
+        let mut nn: Box<NN> = NN::from_seed(3, &configs, 1).with_regularization(Regularization { l1: 0.0, l2: 1e-4, bias: false });
+
+        let samples = samples();
+        let (inputs, outputs) = batch(&samples);
+        let mut workspace = Workspace::new(&nn);
+        let before = nn.score(&samples);
+
+        for _ in 0..200 {
+            nn.train_batch(&mut workspace, &inputs, &outputs, 0.5).unwrap();
+        }
 
-            // update connetions
-            // connection.gradient_w[(curr_node_i, prev_node_i)] += curr_error_z[curr_node_i] * prev_value_a[prev_node_i];
-            // connection.gradient_w += curr_error_z.t().dot(&*prev_value_a);
-            // let mutli = arr1(xs)
-            // connection.gradient_w.axis_iter_mut(Axis(0)).zip(curr_error_z.iter()).for_each(|(mut gradient, error)| {
-            //     gradient.add_assign(prev_value_a.(*error))
-            // });
+        assert!(nn.score(&samples) < before);
+        assert_eq!(nn.blocks().len(), 2);
+        assert!(nn.blocks()[0].norm.is_some());
+    }
 
-            // update bias
-            // curr_gradient_b[curr_node_i] += curr_error_z[curr_node_i];
-            *curr_gradient_b += &*curr_error_z;
+    #[test]
+    fn score_is_the_log_loss_of_the_true_classes() {
+        let configs = [LayerConfig::new(5, ActivationFunction::Tanh), LayerConfig::new(3, ActivationFunction::Softmax)];
+        let nn: Box<NN<f64>> = NN::from_seed(2, &configs, 5);
 
+        let samples: Vec<Sample<f64>> = (0..4).map(|i| Sample {
+            input: Array1::from(vec![i as f64 / 4.0, 1.0 - i as f64]),
+            output: (0..3).map(|class| if class == i % 3 { 1.0 } else { 0.0 }).collect()
+        }).collect();
 
-            // update previous error
-            *prev_error_z = connection.value_w.t().dot(&*curr_error_z);
-            prev_error_z.iter_mut().zip(prev_unscaled_z.iter()).for_each(|(err, unscaled)| {
-                *err *= activation_function_derivate(*unscaled);
-            })
+        let probabilities = nn.predict_batch(&batch(&samples).0);
+        let expected = -samples.iter().zip(probabilities.rows()).map(|(sample, probabilities)| probabilities[sample.output.iter().position(|&y| y == 1.0).unwrap()].ln()).sum::<f64>() / 4.0;
 
-        }   
+        assert!((nn.score(&samples) - expected).abs() < 1e-12, "{} != {expected}", nn.score(&samples));
     }
 
-    fn clear_gradient(&mut self) {
-        for layer in self.layers.iter() {
-            for neuron in layer.gradient_b.borrow_mut().iter_mut() {
-                *neuron = 0.0;
+    #[test]
+    fn a_seed_gives_the_same_run() {
+        // dropout draws from the rng of the network while training
+        let configs = [LayerConfig::new(6, ActivationFunction::Tanh).dropout(0.3), LayerConfig::new(2, ActivationFunction::Sigmoid)];
+        let weights = |nn: &NN| nn.blocks().iter().map(|block| (block.dense.value_w.clone(), block.dense.bias_b.clone())).collect::<Vec<_>>();
+
+        let (inputs, outputs) = batch(&samples());
+        let run = |seed| {
+            let mut nn: Box<NN> = NN::from_seed(3, &configs, seed);
+            let mut workspace = Workspace::new(&nn);
+            let initial = weights(&nn);
+
+            for _ in 0..5 {
+                nn.train_batch(&mut workspace, &inputs, &outputs, 0.5).unwrap();
             }
-        }
 
-        // Matrix
-        for connections in self.connections.iter_mut() {
+            (initial, weights(&nn))
+        };
 
-            // Current activation
-            for connection in connections.gradient_w.iter_mut() {
+        // compared exactly, not within a tolerance
+        assert_eq!(run(7), run(7));
 
-                // previous activation
-                *connection = 0.0;
-            }
-        }
+        let ((initial, trained), (other_initial, other_trained)) = (run(7), run(8));
+        assert_ne!(initial, other_initial);
+        assert_ne!(trained, other_trained);
     }
 
-    fn apply_gradient(&mut self, rate: f32) {
-        for layer in self.layers.iter() {
-            let mut layer_bias_b = layer.bias_b.borrow_mut();
-            let layer_gradient_b = layer.gradient_b.borrow();
+    #[test]
+    fn parallel_training_matches_one_thread() {
+        let configs = [LayerConfig::new(6, ActivationFunction::Tanh), LayerConfig::new(2, ActivationFunction::Sigmoid)];
+        let mut serial: Box<NN> = NN::from_seed(3, &configs, 2);
+        let mut parallel: Box<NN> = NN::from_seed(3, &configs, 2);
 
-            for i in 0..layer.len() {
-                layer_bias_b[i] -= layer_gradient_b[i] * rate
-            }
+        let (inputs, outputs) = batch(&samples());
+        let mut workspace = Workspace::new(&serial);
+        let mut workspaces = vec![Workspace::new(&parallel); 3];
+
+        for _ in 0..5 {
+            serial.train_batch(&mut workspace, &inputs, &outputs, 0.1).unwrap();
+            parallel.train_batch_parallel(&mut workspaces, &inputs, &outputs, 0.1).unwrap();
         }
 
-        // Matrix
-        for connections in self.connections.iter_mut() {
+        for (serial, parallel) in serial.blocks().iter().zip(parallel.blocks()) {
+            let difference = (&serial.dense.value_w - &parallel.dense.value_w).mapv(f32::abs);
 
-            // Current activation
-            // connections.value_w.sub(connections.gradient_w * rate);
+            assert!(difference.iter().all(|&difference| difference < 1e-5));
+        }
+    }
 
-            connections.value_w.iter_mut().zip(connections.gradient_w.iter()).for_each(|(value, gradient)| {
-                *value -= gradient * rate
-            })
+    #[test]
+    fn quantization_reaches_every_layer() {
+        let quantization = FakeQuantization::new(8, Granularity::Layer);
+        let nn: Box<NN> = NN::with_seed(&[3, 4, 2], 3).with_quantization(quantization);
 
-            // for i in 0..connections.gradient_w.len() {
-            //     // previous activation
-            //     for j in 0..connections.gradient_w[i].len() {
-            //         connections.value_w[(i, j)] -= connections.gradient_w[(i, j)] * rate;
-            //     }
-            // }
+        for block in nn.blocks() {
+            assert_eq!(block.dense.quantization, Some(quantization));
+            assert_eq!(block.quantize.quantization, Some(quantization));
         }
+
+        // layers added later get it too
+        let model: Sequential = Sequential::with_seed(&[3], 4).with_quantization(quantization).dense(2, Initializer::GlorotUniform);
+        assert!(matches!(&model.layers[0], LayerKind::Dense(dense) if dense.quantization == Some(quantization)));
     }
 
-    pub fn train_samples(&mut self, samples: &[Sample], rate: f32) {
-        self.clear_gradient();
+    #[test]
+    fn a_step_to_infinity_changes_nothing() {
+        let configs = [LayerConfig::new(6, ActivationFunction::Tanh), LayerConfig::new(2, ActivationFunction::Sigmoid)];
+        let mut nn: Box<NN> = NN::from_seed(3, &configs, 2).with_optimizer(OptimizerFunction::adam());
+        let mut untouched: Box<NN> = NN::from_seed(3, &configs, 2).with_optimizer(OptimizerFunction::adam());
 
-        samples.iter().for_each(|sample| {
-            self.forward(&sample.input);
-            self.backpropagete(&sample.output);
-        });
-    
-        self.apply_gradient(rate / samples.len() as f32);
-    }
+        let (inputs, outputs) = batch(&samples());
+        let mut workspace = Workspace::new(&nn);
 
-    fn error(&mut self, sample: &Sample) -> f32 {
-        let mut total = 0.0;
-    
-        self.forward(&sample.input);
-    
-        let out_layer = &self.layers[self.layers.len() - 1];
-    
-        assert_eq!(sample.output.len(), out_layer.len(), "Output layers not of same size!");
-    
-        let out_value_a = out_layer.value_a.borrow();
-        for i in 0..sample.output.len() {
-            let err = sample.output[i] - out_value_a[i];
-            total += err * err
-        };
-    
-        // divide by 2 because the dericative of squaring is 2*(), so they will cancel out
-        total / sample.output.len() as f32 / 2.0
-    }
-    
-    pub fn score(&mut self, samples: &[Sample]) -> f32 {
-        let mut total = 0.0;
-    
-        samples.iter().for_each(|sample| {
-            total += self.error(sample)
-        });
-    
-        total / samples.len() as f32
-    }
-}
+        nn.train_batch(&mut workspace, &inputs, &outputs, 0.1).unwrap();
+        untouched.train_batch(&mut workspace, &inputs, &outputs, 0.1).unwrap();
 
-enum ActivationFunction {
-    Linear(f32),
-    Sigmoid
-}
+        assert!(matches!(nn.train_batch(&mut workspace, &inputs, &outputs, f32::INFINITY), Err(NNError::NonFiniteParameter { step: 2, .. })));
 
-// TODO: 
-// Is ReLU bad because bias and weights are inittialised from -1 to 1? Is 0 to 1 better?
-
-// const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Linear(0.0); // ReLU
-// const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Linear(0.01);   // Leaky ReLU
-const ACTIVATION_FUNCTION: ActivationFunction = ActivationFunction::Sigmoid;
-
-fn activation_function(value: f32) -> f32 {
-    match ACTIVATION_FUNCTION {
-        ActivationFunction::Sigmoid => {
-            1.0 / (1.0 + f32::powf(std::f32::consts::E, -value))
-        },
-        ActivationFunction::Linear(a) => {
-            if value > 0.0 {
-                value
-            } else {
-                value * a
-            }
-        }
-    }
-}
+        // the moments of the optimizer weren't touched either, the next steps are the same
+        nn.train_batch(&mut workspace, &inputs, &outputs, 0.1).unwrap();
+        untouched.train_batch(&mut workspace, &inputs, &outputs, 0.1).unwrap();
 
-fn activation_function_derivate(value: f32) -> f32 {
-    match ACTIVATION_FUNCTION {
-        ActivationFunction::Sigmoid => {
-            let fun = activation_function(value);
-            fun * (1.0 - fun)
-        },
-        ActivationFunction::Linear(a) => {
-            if value > 0.0 {
-                1.0
-            } else {
-                a
-            }
+        for (nn, untouched) in nn.blocks().iter().zip(untouched.blocks()) {
+            assert_eq!(nn.dense.value_w, untouched.dense.value_w);
+            assert_eq!(nn.dense.bias_b, untouched.dense.bias_b);
         }
     }
-}
\ No newline at end of file
+}
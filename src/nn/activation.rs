@@ -0,0 +1,187 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, ArrayView1, Axis, Dimension, Zip};
+
+use super::Float;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ActivationFunction {
+    #[default]
+    Sigmoid,
+    Tanh,
+    ReLU,
+    LeakyReLU(f32),
+    #[serde(alias = "ELU")]
+    Elu,
+    Softplus,
+    #[serde(alias = "GELU")]
+    Gelu,
+    Swish,
+    Identity,
+    /// Normalizes the whole layer into probabilities, only meaningful on the output layer
+    Softmax
+}
+
+pub(super) fn sigmoid<F: Float>(value: F) -> F {
+    F::one() / (F::one() + (-value).exp())
+}
+
+// constant used by the tanh approximation of GELU, sqrt(2 / pi)
+const GELU_SCALE: f64 = 0.797_884_560_802_865_4;
+const GELU_CUBIC: f64 = 0.044715;
+
+/// The neurons of a layer are along the last axis, any axis before it is a batch of samples
+fn neuron_axis<F, D: Dimension>(values: &Array<F, D>) -> Axis {
+    Axis(values.ndim() - 1)
+}
+
+impl ActivationFunction {
+    pub fn activate<F: Float, D: Dimension>(&self, unscaled: &Array<F, D>) -> Array<F, D> {
+        match self {
+            ActivationFunction::Softmax => {
+                let mut activated = unscaled.clone();
+
+                // subtract the max before exponentiating so that nothing overflows
+                for mut lane in activated.lanes_mut(neuron_axis(unscaled)) {
+                    let log_sum = log_sum_exp(lane.view());
+                    lane.mapv_inplace(|value| (value - log_sum).exp());
+                }
+
+                activated
+            },
+            _ => unscaled.mapv(|value| self.activate_value(value))
+        }
+    }
+
+    /// Turns the error of the activated values into the error of the unscaled ones
+    pub fn derivate<F: Float, D: Dimension>(&self, unscaled: &Array<F, D>, activated: &Array<F, D>, error: &Array<F, D>) -> Array<F, D> {
+        match self {
+            ActivationFunction::Softmax => {
+                let axis = neuron_axis(unscaled);
+                let mut derivate = error.clone();
+
+                // jacobian of softmax is diag(a) - a * a^T
+                Zip::from(derivate.lanes_mut(axis)).and(activated.lanes(axis)).for_each(|mut error, activated| {
+                    let projected = error.dot(&activated);
+
+                    error.zip_mut_with(&activated, |error, &activated| {
+                        *error = activated * (*error - projected)
+                    });
+                });
+
+                derivate
+            },
+            _ => {
+                let mut derivate = error.clone();
+
+                derivate.zip_mut_with(unscaled, |error, unscaled| {
+                    *error *= self.derivate_value(*unscaled)
+                });
+
+                derivate
+            }
+        }
+    }
+
+    fn activate_value<F: Float>(&self, value: F) -> F {
+        let (zero, one, half) = (F::zero(), F::one(), F::of(0.5));
+
+        match self {
+            ActivationFunction::Sigmoid => sigmoid(value),
+            ActivationFunction::Tanh => value.tanh(),
+            ActivationFunction::ReLU => {
+                if value > zero {
+                    value
+                } else {
+                    zero
+                }
+            },
+            ActivationFunction::LeakyReLU(a) => {
+                if value > zero {
+                    value
+                } else {
+                    value * F::of((*a).into())
+                }
+            },
+            ActivationFunction::Elu => {
+                if value > zero {
+                    value
+                } else {
+                    value.exp_m1()
+                }
+            },
+            ActivationFunction::Softplus => {
+                // ln(1 + e^x) without overflowing for big x
+                value.max(zero) + (-value.abs()).exp().ln_1p()
+            },
+            ActivationFunction::Gelu => {
+                let inner = F::of(GELU_SCALE) * (value + F::of(GELU_CUBIC) * value * value * value);
+                half * value * (one + inner.tanh())
+            },
+            ActivationFunction::Swish => value * sigmoid(value),
+            ActivationFunction::Identity => value,
+            ActivationFunction::Softmax => unreachable!("Softmax depends on the whole layer")
+        }
+    }
+
+    fn derivate_value<F: Float>(&self, value: F) -> F {
+        let (zero, one, half) = (F::zero(), F::one(), F::of(0.5));
+
+        match self {
+            ActivationFunction::Sigmoid => {
+                let fun = sigmoid(value);
+                fun * (one - fun)
+            },
+            ActivationFunction::Tanh => {
+                let fun = value.tanh();
+                one - fun * fun
+            },
+            ActivationFunction::ReLU => {
+                if value > zero {
+                    one
+                } else {
+                    zero
+                }
+            },
+            ActivationFunction::LeakyReLU(a) => {
+                if value > zero {
+                    one
+                } else {
+                    F::of((*a).into())
+                }
+            },
+            ActivationFunction::Elu => {
+                if value > zero {
+                    one
+                } else {
+                    value.exp()
+                }
+            },
+            ActivationFunction::Softplus => sigmoid(value),
+            ActivationFunction::Gelu => {
+                let (scale, cubic) = (F::of(GELU_SCALE), F::of(GELU_CUBIC));
+                let inner = scale * (value + cubic * value * value * value);
+                let fun = inner.tanh();
+                let inner_derivate = scale * (one + F::of(3.0) * cubic * value * value);
+
+                half * (one + fun) + half * value * (one - fun * fun) * inner_derivate
+            },
+            ActivationFunction::Swish => {
+                let fun = sigmoid(value);
+                fun + value * fun * (one - fun)
+            },
+            ActivationFunction::Identity => one,
+            ActivationFunction::Softmax => unreachable!("Softmax depends on the whole layer")
+        }
+    }
+}
+
+/// ln(sum(e^x)) computed without overflowing
+pub fn log_sum_exp<F: Float>(values: ArrayView1<F>) -> F {
+    let max = values.fold(F::neg_infinity(), |max, value| max.max(*value));
+
+    if max == F::neg_infinity() {
+        return max
+    }
+
+    max + values.mapv(|value| (value - max).exp()).sum().ln()
+}
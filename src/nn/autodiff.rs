@@ -0,0 +1,553 @@
+use std::ops::Index;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use ndarray::{Array2, ArrayD, ArrayView2, Axis, Ix2, IxDyn};
+
+use super::{NN, Workspace, Float, Mode, ActivationFunction, Loss, LossFunction, Norm, NNError};
+use super::sequential::{Layer, LayerKind, CacheKind};
+use super::quantize::straight_through;
+
+/// A value recorded on a `Tape`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Var(usize);
+
+#[derive(Debug, Clone)]
+enum Op<F: Float> {
+    /// Given from outside, parameters and inputs alike
+    Input,
+    /// Both sides broadcasted to the same shape
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Div(Var, Var),
+    Neg(Var),
+    Scale(Var, F),
+    Powi(Var, i32),
+    Sqrt(Var),
+    Exp(Var),
+    Ln(Var),
+    /// Product of two matrices
+    MatMul(Var, Var),
+    Transpose(Var),
+    Reshape(Var),
+    /// Along the last axis, like the layers of a network
+    Activate(Var, ActivationFunction),
+    /// Summed loss of the activated values given the targets, the targets get no gradient
+    Loss(Var, Var, LossFunction),
+    Sum(Var),
+    SumAxis(Var, usize),
+    Mean(Var),
+    /// Values rounded outside the tape, the gradient goes straight through where the input was in the range
+    StraightThrough(Var, (F, F))
+}
+
+#[derive(Debug, Clone)]
+struct Node<F: Float> {
+    value: ArrayD<F>,
+    op: Op<F>
+}
+
+/// Records every operation on its values, so that `gradients` can go through them backwards.
+/// Arrays are broadcasted like ndarray does, gradients are summed back to the shape of their value.
+#[derive(Debug, Clone, Default)]
+pub struct Tape<F: Float = f32> {
+    nodes: Vec<Node<F>>
+}
+
+/// Sums a gradient over the axes its value was broadcasted along
+fn unbroadcast<F: Float>(mut gradient: ArrayD<F>, shape: &[usize]) -> ArrayD<F> {
+    while gradient.ndim() > shape.len() {
+        gradient = gradient.sum_axis(Axis(0));
+    }
+
+    for (axis, &len) in shape.iter().enumerate() {
+        if len == 1 && gradient.shape()[axis] != 1 {
+            gradient = gradient.sum_axis(Axis(axis)).insert_axis(Axis(axis));
+        }
+    }
+
+    gradient
+}
+
+fn matrix<F: Float>(values: &ArrayD<F>) -> ArrayView2<'_, F> {
+    values.view().into_dimensionality::<Ix2>().expect("Matrix products need matrices")
+}
+
+impl<F: Float> Tape<F> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, value: ArrayD<F>, op: Op<F>) -> Var {
+        self.nodes.push(Node { value, op });
+
+        Var(self.nodes.len() - 1)
+    }
+
+    /// Values from outside the tape, their gradient is computed like any other
+    pub fn input(&mut self, value: ArrayD<F>) -> Var {
+        self.push(value, Op::Input)
+    }
+
+    pub fn value(&self, var: Var) -> &ArrayD<F> {
+        &self.nodes[var.0].value
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) + self.value(b);
+        self.push(value, Op::Add(a, b))
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) - self.value(b);
+        self.push(value, Op::Sub(a, b))
+    }
+
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) * self.value(b);
+        self.push(value, Op::Mul(a, b))
+    }
+
+    pub fn div(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) / self.value(b);
+        self.push(value, Op::Div(a, b))
+    }
+
+    pub fn neg(&mut self, a: Var) -> Var {
+        let value = -self.value(a).clone();
+        self.push(value, Op::Neg(a))
+    }
+
+    pub fn scale(&mut self, a: Var, factor: F) -> Var {
+        let value = self.value(a) * factor;
+        self.push(value, Op::Scale(a, factor))
+    }
+
+    pub fn powi(&mut self, a: Var, n: i32) -> Var {
+        let value = self.value(a).mapv(|value| value.powi(n));
+        self.push(value, Op::Powi(a, n))
+    }
+
+    pub fn sqrt(&mut self, a: Var) -> Var {
+        let value = self.value(a).mapv(F::sqrt);
+        self.push(value, Op::Sqrt(a))
+    }
+
+    pub fn exp(&mut self, a: Var) -> Var {
+        let value = self.value(a).mapv(F::exp);
+        self.push(value, Op::Exp(a))
+    }
+
+    pub fn ln(&mut self, a: Var) -> Var {
+        let value = self.value(a).mapv(F::ln);
+        self.push(value, Op::Ln(a))
+    }
+
+    /// (n x m) . (m x p)
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let value = matrix(self.value(a)).dot(&matrix(self.value(b))).into_dyn();
+        self.push(value, Op::MatMul(a, b))
+    }
+
+    pub fn transpose(&mut self, a: Var) -> Var {
+        let value = matrix(self.value(a)).t().to_owned().into_dyn();
+        self.push(value, Op::Transpose(a))
+    }
+
+    pub fn reshape(&mut self, a: Var, shape: &[usize]) -> Var {
+        let value = self.value(a).as_standard_layout().into_owned().into_shape(IxDyn(shape)).expect("Can't reshape");
+        self.push(value, Op::Reshape(a))
+    }
+
+    pub fn activate(&mut self, a: Var, activation: ActivationFunction) -> Var {
+        let value = activation.activate(self.value(a));
+        self.push(value, Op::Activate(a, activation))
+    }
+
+    /// Probabilities along the last axis
+    pub fn softmax(&mut self, a: Var) -> Var {
+        self.activate(a, ActivationFunction::Softmax)
+    }
+
+    /// Summed loss of every sample, a scalar
+    pub fn loss(&mut self, activated: Var, target: Var, loss: LossFunction) -> Var {
+        let value = ArrayD::from_elem(IxDyn(&[]), loss.loss(self.value(activated), self.value(target)));
+        self.push(value, Op::Loss(activated, target, loss))
+    }
+
+    /// Sum of every value, a scalar
+    pub fn sum(&mut self, a: Var) -> Var {
+        let value = ArrayD::from_elem(IxDyn(&[]), self.value(a).sum());
+        self.push(value, Op::Sum(a))
+    }
+
+    /// Sum along an axis, removing it
+    pub fn sum_axis(&mut self, a: Var, axis: usize) -> Var {
+        let value = self.value(a).sum_axis(Axis(axis));
+        self.push(value, Op::SumAxis(a, axis))
+    }
+
+    /// Mean of every value, a scalar
+    pub fn mean(&mut self, a: Var) -> Var {
+        let value = ArrayD::from_elem(IxDyn(&[]), self.value(a).mean().unwrap_or(F::zero()));
+        self.push(value, Op::Mean(a))
+    }
+
+    /// `rounded` in place of the values of `a`, like fake quantization in `range` does.
+    /// The rounding is ignored by the gradient, except where the values were clamped to the range.
+    pub fn straight_through(&mut self, a: Var, rounded: ArrayD<F>, range: (F, F)) -> Var {
+        assert_eq!(self.value(a).shape(), rounded.shape(), "Rounded values not of same size!");
+
+        self.push(rounded, Op::StraightThrough(a, range))
+    }
+
+    /// Gradient of a scalar with respect to everything recorded before it
+    pub fn gradients(&self, output: Var) -> Gradients<F> {
+        assert_eq!(self.value(output).len(), 1, "Gradients are of a scalar");
+
+        let mut gradients: Vec<Option<ArrayD<F>>> = vec![None; output.0 + 1];
+        gradients[output.0] = Some(ArrayD::ones(self.value(output).raw_dim()));
+
+        for i in (0..=output.0).rev() {
+            let Some(gradient) = gradients[i].take() else { continue };
+            let node = &self.nodes[i];
+
+            for (var, input_gradient) in self.backward(node, &gradient) {
+                let shape = self.value(var).shape();
+                let input_gradient = unbroadcast(input_gradient, shape);
+
+                match &mut gradients[var.0] {
+                    Some(total) => *total += &input_gradient,
+                    empty => *empty = Some(input_gradient)
+                }
+            }
+
+            gradients[i] = Some(gradient);
+        }
+
+        Gradients { gradients }
+    }
+
+    /// Gradients of the inputs of a node given the gradient of its value
+    fn backward(&self, node: &Node<F>, gradient: &ArrayD<F>) -> Vec<(Var, ArrayD<F>)> {
+        match node.op {
+            Op::Input => vec![],
+            Op::Add(a, b) => vec![(a, gradient.clone()), (b, gradient.clone())],
+            Op::Sub(a, b) => vec![(a, gradient.clone()), (b, -gradient.clone())],
+            Op::Mul(a, b) => vec![(a, gradient * self.value(b)), (b, gradient * self.value(a))],
+            Op::Div(a, b) => {
+                let b_value = self.value(b);
+
+                vec![(a, gradient / b_value), (b, -(gradient * &node.value) / b_value)]
+            },
+            Op::Neg(a) => vec![(a, -gradient.clone())],
+            Op::Scale(a, factor) => vec![(a, gradient * factor)],
+            Op::Powi(a, n) => vec![(a, gradient * &self.value(a).mapv(|value| F::of(n.into()) * value.powi(n - 1)))],
+            Op::Sqrt(a) => vec![(a, gradient / &(&node.value * F::of(2.0)))],
+            Op::Exp(a) => vec![(a, gradient * &node.value)],
+            Op::Ln(a) => vec![(a, gradient / self.value(a))],
+            Op::MatMul(a, b) => {
+                let gradient = matrix(gradient);
+
+                vec![
+                    (a, gradient.dot(&matrix(self.value(b)).t()).into_dyn()),
+                    (b, matrix(self.value(a)).t().dot(&gradient).into_dyn())
+                ]
+            },
+            Op::Transpose(a) => vec![(a, matrix(gradient).t().to_owned().into_dyn())],
+            Op::Reshape(a) => {
+                let shape = self.value(a).shape();
+
+                vec![(a, gradient.as_standard_layout().into_owned().into_shape(IxDyn(shape)).expect("Gradient of the reshaped value"))]
+            },
+            Op::Activate(a, activation) => vec![(a, activation.derivate(self.value(a), &node.value, gradient))],
+            Op::Loss(activated, target, loss) => {
+                vec![(activated, loss.derivate(self.value(activated), self.value(target)) * gradient.sum())]
+            },
+            Op::Sum(a) => vec![(a, ArrayD::from_elem(self.value(a).raw_dim(), gradient.sum()))],
+            Op::SumAxis(a, axis) => {
+                let shape = self.value(a).raw_dim();
+
+                vec![(a, gradient.view().insert_axis(Axis(axis)).broadcast(shape).expect("Gradient of the summed axis").to_owned())]
+            },
+            Op::Mean(a) => {
+                let value = self.value(a);
+
+                vec![(a, ArrayD::from_elem(value.raw_dim(), gradient.sum() / F::of(value.len().max(1) as f64)))]
+            },
+            Op::StraightThrough(a, range) => {
+                let mut gradient = gradient.clone();
+                straight_through(&mut gradient, self.value(a), range);
+
+                vec![(a, gradient)]
+            }
+        }
+    }
+}
+
+/// Gradients of everything that led to a value, see `Tape::gradients`
+#[derive(Debug, Clone)]
+pub struct Gradients<F: Float = f32> {
+    gradients: Vec<Option<ArrayD<F>>>
+}
+
+impl<F: Float> Gradients<F> {
+    /// `None` when the value didn't contribute
+    pub fn get(&self, var: Var) -> Option<&ArrayD<F>> {
+        self.gradients.get(var.0).and_then(Option::as_ref)
+    }
+}
+
+impl<F: Float> Index<Var> for Gradients<F> {
+    type Output = ArrayD<F>;
+
+    fn index(&self, var: Var) -> &ArrayD<F> {
+        self.get(var).expect("Value didn't contribute")
+    }
+}
+
+/// A network recorded on a tape, with its parameters as inputs of the tape
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    /// Activated values of the output layer
+    pub output: Var,
+    /// One list per layer in the order of `Layer::parameters`, empty for the layers without any
+    pub parameters: Vec<Vec<Var>>
+}
+
+/// Mean along `axis` of a matrix, kept as a row or a column so that it broadcasts back over the matrix
+fn mean_axis<F: Float>(tape: &mut Tape<F>, a: Var, axis: usize) -> Var {
+    let mut shape = tape.value(a).shape().to_vec();
+    let len = std::mem::replace(&mut shape[axis], 1);
+
+    let sum = tape.sum_axis(a, axis);
+    let sum = tape.reshape(sum, &shape);
+
+    tape.scale(sum, F::one() / F::of(len as f64))
+}
+
+/// Normalizes with the same statistics as `Norm::forward`, then applies the gain and the bias
+fn record_norm<F: Float>(tape: &mut Tape<F>, norm: &Norm<F>, unscaled_z: Var, mode: Mode) -> (Var, Vec<Var>) {
+    let (gamma, beta, epsilon) = match norm {
+        Norm::Batch(norm) => (&norm.gamma, &norm.beta, norm.epsilon),
+        Norm::Layer(norm) => (&norm.gamma, &norm.beta, norm.epsilon)
+    };
+
+    let (mean, var) = match norm {
+        Norm::Batch(norm) if mode != Mode::Train => {
+            (tape.input(norm.running_mean.clone().into_dyn()), tape.input(norm.running_var.clone().into_dyn()))
+        },
+        // every neuron over the batch, or every sample over its neurons
+        _ => {
+            let axis = if matches!(norm, Norm::Batch(_)) { 0 } else { 1 };
+
+            let mean = mean_axis(tape, unscaled_z, axis);
+            let centered = tape.sub(unscaled_z, mean);
+            let squared = tape.powi(centered, 2);
+
+            (mean, mean_axis(tape, squared, axis))
+        }
+    };
+
+    let epsilon = tape.input(ArrayD::from_elem(IxDyn(&[]), F::of(epsilon.into())));
+    let var = tape.add(var, epsilon);
+    let std = tape.sqrt(var);
+
+    let centered = tape.sub(unscaled_z, mean);
+    let normalized = tape.div(centered, std);
+
+    let gamma = tape.input(gamma.clone().into_dyn());
+    let beta = tape.input(beta.clone().into_dyn());
+    let scaled = tape.mul(normalized, gamma);
+
+    (tape.add(scaled, beta), vec![gamma, beta])
+}
+
+impl<F: Float> NN<F> {
+    /// Records feeding `inputs` through the network like `forward` does: the weights and the values are rounded
+    /// with fake quantization, neurons are dropped out using `rng` while in `Mode::Train`, and the layers that track
+    /// statistics leave their caches in `workspace`.
+    pub fn record(&self, tape: &mut Tape<F>, inputs: Var, workspace: &mut Workspace<F>, rng: &mut StdRng) -> Result<Recorded, NNError> {
+        let mode = self.mode();
+
+        let mut parameters = Vec::new();
+        let mut value_a = inputs;
+
+        for (layer_i, layer) in self.layers.iter().enumerate() {
+            let mut layer_parameters = Vec::new();
+
+            value_a = match layer {
+                LayerKind::Dense(dense) => {
+                    // the weights as the forward pass sees them, their gradient goes straight through to the float ones
+                    let value_w = tape.input(dense.weights().into_owned().into_dyn());
+                    let bias_b = tape.input(dense.bias_b.clone().into_dyn());
+
+                    layer_parameters = vec![value_w, bias_b];
+
+                    // (batch x prev) . (prev x curr), the bias is broadcasted to every sample
+                    let transposed = tape.transpose(value_w);
+                    let unscaled_z = tape.matmul(value_a, transposed);
+
+                    tape.add(unscaled_z, bias_b)
+                },
+                LayerKind::Activation(activation) => tape.activate(value_a, *activation),
+                LayerKind::Norm(norm) => {
+                    // only for the statistics of the batch the running ones are tracked with
+                    layer.forward(tape.value(value_a).clone(), &mut workspace.caches[layer_i], mode, rng);
+
+                    let (normalized, norm_parameters) = record_norm(tape, norm, value_a, mode);
+                    layer_parameters = norm_parameters;
+
+                    normalized
+                },
+                // the layers round and drop out themselves, so that the ranges and the masks are those of `forward`
+                LayerKind::Quantize(_) | LayerKind::Dropout(_) => {
+                    let output = layer.forward(tape.value(value_a).clone(), &mut workspace.caches[layer_i], mode, rng);
+
+                    match &workspace.caches[layer_i] {
+                        CacheKind::Quantize(cache) => match cache.used() {
+                            Some(range) => tape.straight_through(value_a, output, range),
+                            None => value_a
+                        },
+                        CacheKind::Dropout(Some(mask)) => {
+                            let mask = tape.input(mask.clone());
+
+                            tape.mul(value_a, mask)
+                        },
+                        _ => value_a
+                    }
+                },
+                _ => return Err(NNError::Unrecordable { layer: layer_i })
+            };
+
+            parameters.push(layer_parameters);
+        }
+
+        Ok(Recorded { output: value_a, parameters })
+    }
+
+    /// Same as `train_batch`, with the gradients coming from a tape instead of backpropagation
+    pub fn train_batch_taped(&mut self, workspace: &mut Workspace<F>, inputs: &Array2<F>, outputs: &Array2<F>, rate: F) -> Result<(), NNError> {
+        if inputs.nrows() == 0 {
+            return Ok(())
+        }
+
+        let mut rng = StdRng::seed_from_u64(self.rng().gen());
+
+        workspace.clear_gradient();
+
+        let mut tape = Tape::new();
+        let input = tape.input(inputs.clone().into_dyn());
+        let target = tape.input(outputs.clone().into_dyn());
+
+        let recorded = self.record(&mut tape, input, workspace, &mut rng)?;
+        let loss = tape.loss(recorded.output, target, self.loss);
+        let gradients = tape.gradients(loss);
+
+        for (layer_gradients, parameters) in workspace.gradients.iter_mut().zip(&recorded.parameters) {
+            for (gradient, &parameter) in layer_gradients.iter_mut().zip(parameters) {
+                gradient.assign(&gradients[parameter]);
+            }
+        }
+
+        self.apply_gradient(std::slice::from_ref(workspace), rate, inputs.nrows())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{LayerConfig, FakeQuantization, Normalization, batch, samples};
+    use crate::nn::sequential::{Pool, PoolKind};
+
+    fn configs(norm: Normalization) -> [LayerConfig; 2] {
+        [LayerConfig::new(6, ActivationFunction::Tanh).dropout(0.3).norm(norm), LayerConfig::new(2, ActivationFunction::Sigmoid)]
+    }
+
+    #[test]
+    fn taped_training_matches_backpropagation() {
+        for norm in [Normalization::None, Normalization::batch(), Normalization::layer()] {
+            let nn = || NN::from_seed(3, &configs(norm), 4).with_quantization(FakeQuantization::int8());
+            let (mut backpropagated, mut taped): (Box<NN>, Box<NN>) = (nn(), nn());
+
+            let (inputs, outputs) = batch(&samples());
+            let mut workspace = Workspace::new(&taped);
+
+            for _ in 0..5 {
+                backpropagated.train_batch(&mut workspace, &inputs, &outputs, 0.5).unwrap();
+                taped.train_batch_taped(&mut workspace, &inputs, &outputs, 0.5).unwrap();
+            }
+
+            for (backpropagated, taped) in backpropagated.layers.iter().zip(&taped.layers) {
+                for (backpropagated, taped) in backpropagated.parameters().iter().zip(taped.parameters()) {
+                    let difference = (backpropagated - &taped).mapv(f32::abs);
+
+                    assert!(difference.iter().all(|&difference| difference < 1e-5), "{norm:?}: {difference}");
+                }
+
+                if let (LayerKind::Norm(Norm::Batch(backpropagated)), LayerKind::Norm(Norm::Batch(taped))) = (backpropagated, taped) {
+                    assert!((&backpropagated.running_mean - &taped.running_mean).iter().all(|difference| difference.abs() < 1e-5));
+                }
+            }
+
+            for (backpropagated, taped) in backpropagated.blocks().iter().zip(taped.blocks()) {
+                let ((min, max), (taped_min, taped_max)) = (backpropagated.quantize.range.unwrap(), taped.quantize.range.unwrap());
+
+                assert!((min - taped_min).abs() < 1e-5 && (max - taped_max).abs() < 1e-5, "{norm:?}: {min}..{max} and {taped_min}..{taped_max}");
+            }
+        }
+    }
+
+    #[test]
+    fn recorded_evaluation_matches_predict() {
+        let mut nn: Box<NN> = NN::from_seed(3, &configs(Normalization::batch()), 4);
+        let (inputs, outputs) = batch(&samples());
+        let mut workspace = Workspace::new(&nn);
+
+        // running statistics away from their initial values
+        nn.train_batch(&mut workspace, &inputs, &outputs, 0.5).unwrap();
+        nn.set_mode(Mode::Eval);
+
+        let mut tape = Tape::new();
+        let input = tape.input(inputs.clone().into_dyn());
+        let recorded = nn.record(&mut tape, input, &mut workspace, &mut StdRng::seed_from_u64(0)).unwrap();
+
+        let difference = (tape.value(recorded.output) - &nn.predict_batch(&inputs).into_dyn()).mapv(f32::abs);
+
+        assert!(difference.iter().all(|&difference| difference < 1e-6), "{difference}");
+    }
+
+    #[test]
+    fn empty_batch_changes_nothing() {
+        let mut nn: Box<NN<f64>> = NN::from_seed(3, &[LayerConfig::new(4, ActivationFunction::Tanh), LayerConfig::new(2, ActivationFunction::Sigmoid)], 4);
+        let before = nn.clone();
+        let mut workspace = Workspace::new(&nn);
+
+        nn.train_batch_taped(&mut workspace, &Array2::zeros((0, 3)), &Array2::zeros((0, 2)), 0.5).unwrap();
+
+        assert_eq!(nn.layers[0].parameters(), before.layers[0].parameters());
+    }
+
+    #[test]
+    fn pooling_is_not_recorded() {
+        let mut nn: Box<NN> = NN::from_seed(3, &configs(Normalization::None), 4);
+        let mut workspace = Workspace::new(&nn);
+
+        nn.layers[1] = LayerKind::Pool(Pool::new(PoolKind::Max, 1, 1));
+
+        let mut tape = Tape::new();
+        let inputs = tape.input(batch(&samples()).0.into_dyn());
+
+        let recorded = nn.record(&mut tape, inputs, &mut workspace, &mut StdRng::seed_from_u64(0));
+
+        assert!(matches!(recorded, Err(NNError::Unrecordable { layer: 1 })));
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, Dimension};
+use num_traits::clamp;
+
+use super::Float;
+
+/// Limits the averaged gradients before the optimizer sees them,
+/// first by the norm of all of them together, then value by value
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Clipping {
+    /// Maximum global norm of the gradients of the whole network
+    pub norm: Option<f32>,
+    /// Maximum absolute value of any single gradient
+    pub value: Option<f32>
+}
+
+impl Clipping {
+    pub fn norm(max: f32) -> Self {
+        Self { norm: Some(max), ..Default::default() }
+    }
+
+    pub fn value(max: f32) -> Self {
+        Self { value: Some(max), ..Default::default() }
+    }
+
+    /// Factor that brings a global norm of the gradients under the maximum
+    pub(super) fn scale<F: Float>(&self, norm: F) -> F {
+        match self.norm.map(|max| F::of(max.into())) {
+            Some(max) if norm > max => max / norm,
+            _ => F::one()
+        }
+    }
+
+    pub(super) fn clip<F: Float, D: Dimension>(&self, gradient: &mut Array<F, D>) {
+        if let Some(max) = self.value {
+            let max = F::of(max.into());
+
+            gradient.mapv_inplace(|gradient| clamp(gradient, -max, max))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array1;
+
+    use super::*;
+    use crate::nn::{Graph, Initializer, samples};
+    use crate::nn::sequential::Layer;
+
+    fn parameters(graph: &Graph<f64>) -> Array1<f64> {
+        graph.layers.iter().flat_map(|layer| layer.parameters()).flat_map(|parameter| parameter.iter().copied().collect::<Vec<_>>()).collect()
+    }
+
+    /// The gradient the optimizer was given, plain gradient descent of rate 1 steps by minus it
+    fn gradient(clipping: Clipping) -> Array1<f64> {
+        let mut graph: Graph<f64> = Graph::with_seed(1).with_clipping(clipping);
+        let input = graph.input(&[3]);
+        graph.dense(input, 2, Initializer::GlorotUniform);
+
+        let before = parameters(&graph);
+        graph.train_samples(&samples(), 1.0).unwrap();
+
+        before - parameters(&graph)
+    }
+
+    fn norm(gradient: &Array1<f64>) -> f64 {
+        gradient.dot(gradient).sqrt()
+    }
+
+    #[test]
+    fn norm_is_rescaled_to_the_maximum() {
+        let unclipped = gradient(Clipping::default());
+        let clipped = gradient(Clipping::norm(0.5));
+
+        assert!(norm(&unclipped) > 0.5);
+        assert!((norm(&clipped) - 0.5).abs() < 1e-6, "{}", norm(&clipped));
+
+        // the direction is kept
+        let expected = &unclipped * (0.5 / norm(&unclipped));
+        assert!(clipped.iter().zip(&expected).all(|(clipped, expected)| (clipped - expected).abs() < 1e-6), "{clipped} != {expected}");
+
+        // a norm under the maximum is left alone
+        assert!((gradient(Clipping::norm(1e6)) - &unclipped).iter().all(|difference| difference.abs() < 1e-12));
+    }
+
+    #[test]
+    fn values_are_clamped_one_by_one() {
+        let unclipped = gradient(Clipping::default());
+        let clipped = gradient(Clipping::value(0.3));
+
+        assert!(unclipped.iter().any(|value| value.abs() > 0.3) && unclipped.iter().any(|value| value.abs() < 0.3));
+
+        for (clipped, unclipped) in clipped.iter().zip(&unclipped) {
+            assert!((clipped - unclipped.clamp(-0.3, 0.3)).abs() < 1e-6, "{clipped} from {unclipped}");
+        }
+    }
+}
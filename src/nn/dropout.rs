@@ -0,0 +1,29 @@
+use serde::{Serialize, Deserialize};
+use rand::Rng;
+use ndarray::{Array2, Ix2};
+
+use super::Float;
+
+/// Whether the network is being trained or evaluated.
+/// Dropout is only applied while training, `predict` and `score` always evaluate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    Train,
+    Eval
+}
+
+/// Inverted dropout, every value is either dropped or scaled by 1 / (1 - rate)
+/// so that the expected value stays the same and evaluation needs no scaling
+pub(super) fn mask<F: Float>(dim: Ix2, rate: f32, rng: &mut impl Rng) -> Array2<F> {
+    let keep = 1.0 - rate;
+    let scale = F::one() / F::of(keep.into());
+
+    Array2::from_shape_simple_fn(dim, || {
+        if rng.gen::<f32>() < keep {
+            scale
+        } else {
+            F::zero()
+        }
+    })
+}
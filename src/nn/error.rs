@@ -0,0 +1,25 @@
+use std::fmt;
+
+/// Training stopped before corrupting the network.
+/// Layers are numbered like `Graph::layers`, for a `NN` like the layers of its model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NNError {
+    /// Backpropagation produced a NaN or infinite gradient, nothing was applied
+    NonFiniteGradient { layer: usize, step: i32 },
+    /// Applying the gradients would produce a NaN or infinite parameter, nothing was changed
+    NonFiniteParameter { layer: usize, step: i32 },
+    /// The layer has no operations on a `Tape`, like convolutions or recurrent cells
+    Unrecordable { layer: usize }
+}
+
+impl fmt::Display for NNError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonFiniteGradient { layer, step } => write!(f, "Non finite gradient in layer {layer} at step {step}"),
+            Self::NonFiniteParameter { layer, step } => write!(f, "Non finite parameter in layer {layer} at step {step}"),
+            Self::Unrecordable { layer } => write!(f, "Layer {layer} can't be recorded on a tape")
+        }
+    }
+}
+
+impl std::error::Error for NNError { }
@@ -0,0 +1,17 @@
+use std::iter::Sum;
+
+use ndarray::NdFloat;
+use num_traits::FromPrimitive;
+
+/// Precision a network computes in, `f32` or `f64`.
+/// Hyperparameters are `f32` whatever the precision, they are converted where they are used.
+pub trait Float: NdFloat + FromPrimitive + Default + Sum {
+    /// Constants and hyperparameters, which are always in range
+    fn of(value: f64) -> Self {
+        Self::from_f64(value).expect("Float out of range")
+    }
+}
+
+impl Float for f32 { }
+
+impl Float for f64 { }
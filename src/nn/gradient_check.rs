@@ -0,0 +1,191 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use ndarray::{Array, Dimension};
+
+use super::{Graph, Sample, Workspace, Float, batch};
+use super::sequential::Layer;
+
+/// Relative error between the gradients of backpropagation and the numerical ones of a layer,
+/// |analytic - numerical| / (|analytic| + |numerical|) over each of its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradientError<F = f32> {
+    /// Numbered like `Graph::layers`
+    pub layer: usize,
+    /// In the order of `Layer::parameters`, for a dense layer the weights then the bias
+    pub parameters: Vec<F>
+}
+
+impl<F: Float> GradientError<F> {
+    /// Worst error of the layer
+    pub fn max(&self) -> F {
+        self.parameters.iter().fold(F::zero(), |max, &error| max.max(error))
+    }
+}
+
+/// Compares the gradients of every parameter found by backpropagation against
+/// central differences (loss(p + eps) - loss(p - eps)) / 2eps, one error per layer with parameters.
+/// The loss is the average over the samples plus the regularization penalty.
+/// The same neurons are dropped out in every pass, and the parameters are restored afterwards.
+pub fn gradient_check<F: Float>(graph: &mut Graph<F>, samples: &[Sample<F>], eps: F) -> Vec<GradientError<F>> {
+    let (inputs, outputs) = batch(samples);
+    let (inputs, outputs) = (inputs.into_dyn(), outputs.into_dyn());
+    let scale = F::one() / F::of(samples.len() as f64);
+    let seed = graph.rng().gen();
+
+    // analytic
+    let mut workspace = Workspace::new(graph);
+
+    graph.sum_gradients(&mut workspace, graph.split(inputs.clone()), &outputs, &mut StdRng::seed_from_u64(seed));
+
+    // numerical
+    let mut scratch = Workspace::new(graph);
+
+    // recurrent layers start every pass over
+    let mut loss = |graph: &Graph<F>| -> F {
+        scratch.clear_states();
+
+        graph.forward_error(&mut scratch, graph.split(inputs.clone()), &outputs, &mut StdRng::seed_from_u64(seed)) * scale + graph.penalty()
+    };
+
+    let mut numerical = |graph: &mut Graph<F>, layer_i: usize, parameter_i: usize| -> Vec<F> {
+        let len = graph.layers[layer_i].parameters()[parameter_i].len();
+
+        (0..len).map(|i| {
+            let original = value(graph, layer_i, parameter_i, i, None);
+
+            value(graph, layer_i, parameter_i, i, Some(original + eps));
+            let plus = loss(graph);
+
+            value(graph, layer_i, parameter_i, i, Some(original - eps));
+            let minus = loss(graph);
+
+            value(graph, layer_i, parameter_i, i, Some(original));
+
+            (plus - minus) / (eps + eps)
+        }).collect()
+    };
+
+    (0..graph.layers.len()).filter(|&layer_i| !workspace.gradients[layer_i].is_empty()).map(|layer_i| {
+        let regularization = graph.regularization;
+        let kinds = graph.layers[layer_i].kinds();
+
+        let parameters = workspace.gradients[layer_i].iter().zip(kinds).enumerate().map(|(parameter_i, (gradient, kind))| {
+            let mut analytic = gradient * scale;
+
+            if regularization.applies(kind) {
+                regularization.add_gradient(&graph.layers[layer_i].parameters()[parameter_i], &mut analytic);
+            }
+
+            relative_error(&analytic, &numerical(graph, layer_i, parameter_i))
+        }).collect();
+
+        GradientError { layer: layer_i, parameters }
+    }).collect()
+}
+
+/// The `i`th value of a parameter in logical order, replaced by `new` when given
+fn value<F: Float>(graph: &mut Graph<F>, layer_i: usize, parameter_i: usize, i: usize, new: Option<F>) -> F {
+    let mut parameters = graph.layers[layer_i].parameters_mut();
+    let value = &mut parameters[parameter_i].as_slice_mut().expect("Parameters in standard layout")[i];
+
+    if let Some(new) = new {
+        *value = new;
+    }
+
+    *value
+}
+
+// gradients that should be exactly 0, like a bias before a batch normalization,
+// only differ by rounding errors so they are compared in absolute terms
+const VANISHING: f64 = 1e-4;
+
+fn relative_error<F: Float, D: Dimension>(analytic: &Array<F, D>, numerical: &[F]) -> F {
+    let norm = |values: &mut dyn Iterator<Item = F>| values.map(|value| value * value).sum::<F>().sqrt();
+
+    let difference = norm(&mut analytic.iter().zip(numerical.iter()).map(|(&analytic, &numerical)| analytic - numerical));
+    let total = norm(&mut analytic.iter().copied()) + norm(&mut numerical.iter().copied());
+
+    difference / total.max(F::of(VANISHING))
+}
+
+/// Samples for the gradient checks of the layers: `inputs` values between -0.4 and 0.6,
+/// and one of `classes` outputs set for each
+#[cfg(test)]
+pub(crate) fn one_hot_samples(inputs: usize, classes: usize) -> Vec<Sample<f64>> {
+    (0..6).map(|i| Sample {
+        input: ndarray::Array1::from_shape_fn(inputs, |j| ((i * 7 + j * 3) % 11) as f64 / 11.0 - 0.4),
+        output: (0..classes).map(|j| if j == i % classes { 1.0 } else { 0.0 }).collect()
+    }).collect()
+}
+
+/// Every gradient of `graph` found by backpropagation is within `tolerance` of the numerical one
+#[cfg(test)]
+pub(crate) fn assert_gradients(graph: &mut Graph<f64>, samples: &[Sample<f64>], tolerance: f64) {
+    for error in gradient_check(graph, samples, 1e-5) {
+        assert!(error.max() < tolerance, "{error:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{NN, LayerConfig, ActivationFunction, LossFunction, Initializer, Normalization, Regularization};
+
+    const TOLERANCE: f64 = 1e-8;
+
+    /// Targets are one-hot when `probabilities`, any value in [0, 1] otherwise
+    /// The inputs of `one_hot_samples` with targets in 0..1 instead
+    fn targets(inputs: usize, outputs: usize) -> Vec<Sample<f64>> {
+        one_hot_samples(inputs, outputs).into_iter().enumerate().map(|(i, sample)| Sample {
+            output: (0..outputs).map(|j| ((i + j * 5) % 7) as f64 / 7.0).collect(),
+            ..sample
+        }).collect()
+    }
+
+    #[test]
+    fn every_activation() {
+        use ActivationFunction::*;
+
+        for activation in [Sigmoid, Tanh, ReLU, LeakyReLU(0.1), Elu, Softplus, Gelu, Swish, Identity, Softmax] {
+            let configs = [LayerConfig::new(5, activation).bias(Initializer::Uniform(0.5)), LayerConfig::new(3, Sigmoid)];
+            let mut nn: Box<NN<f64>> = NN::from_seed(4, &configs, 1);
+
+            assert_gradients(&mut nn, &targets(4, 3), TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn every_loss() {
+        use LossFunction::*;
+
+        // softmax switches the loss to cross entropy when added, every loss is set afterwards
+        for output in [ActivationFunction::Sigmoid, ActivationFunction::Softmax] {
+            for loss in [Mse, Mae, Huber(0.1), BinaryCrossEntropy, CategoricalCrossEntropy, Focal(2.0)] {
+                let configs = [LayerConfig::new(5, ActivationFunction::Tanh), LayerConfig::new(3, output)];
+                let mut nn: Box<NN<f64>> = NN::from_seed(4, &configs, 2);
+                nn.loss = loss;
+
+                assert_gradients(&mut nn, &one_hot_samples(4, 3), TOLERANCE);
+            }
+        }
+    }
+
+    #[test]
+    fn normalizations_with_l1_and_l2() {
+        for norm in [Normalization::batch(), Normalization::layer()] {
+            let configs = [LayerConfig::new(5, ActivationFunction::Tanh).norm(norm).dropout(0.3), LayerConfig::new(3, ActivationFunction::Softmax)];
+            let mut nn: Box<NN<f64>> = NN::from_seed(4, &configs, 3)
+                .with_regularization(Regularization { l1: 0.01, l2: 0.1, bias: true });
+
+            let errors = gradient_check(&mut nn, &one_hot_samples(4, 3), 1e-5);
+
+            for error in errors {
+                for (i, &parameter) in error.parameters.iter().enumerate() {
+                    // a batch normalization cancels the bias before it, only rounding errors are left of its gradient of 0
+                    let vanishing = norm == Normalization::batch() && error.layer == 1 && i == 1;
+
+                    assert!(parameter < if vanishing { 1e-6 } else { TOLERANCE }, "{error:?}");
+                }
+            }
+        }
+    }
+}
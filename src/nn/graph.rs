@@ -0,0 +1,862 @@
+use serde::{Serialize, Deserialize};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use ndarray::{s, concatenate, ArrayD, ArrayViewD, Axis, IxDyn};
+
+use super::{Input, Sample, Output, ActivationFunction, Loss, LossFunction, Optimizer, OptimizerFunction, OptimizerState, Initializer, Regularization, Clipping, NNError, Mode, Normalization, Float, Workspace, batch};
+use super::sequential::{Layer, LayerKind, ParameterKind, CacheKind, StateKind, Dense, Dropout, Reshape, Conv2D, Pool, PoolKind, Recurrent, Cell, Attention, Positional, Encoder, PatchEmbedding, Quantize};
+use super::quantize::FakeQuantization;
+use super::prune::zero_pruned;
+
+/// A node of a `Graph`, the values it takes from other nodes are given by their index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Node {
+    /// One of the inputs of the model
+    Input(usize),
+    /// One of the layers of the model, fed by one node
+    Layer { layer: usize, input: usize },
+    /// Sum of nodes of the same shape
+    Add(Vec<usize>),
+    /// Nodes joined along their last axis
+    Concat(Vec<usize>)
+}
+
+impl Node {
+    /// Nodes this one is fed by
+    pub fn inputs(&self) -> Vec<usize> {
+        match self {
+            Node::Input(_) => Vec::new(),
+            Node::Layer { input, .. } => vec![*input],
+            Node::Add(nodes) | Node::Concat(nodes) => nodes.clone()
+        }
+    }
+}
+
+/// Layers connected in any directed acyclic graph: skip connections, merges and multiple inputs.
+/// Samples hold every input one after the other, flat, and are split to the input shapes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Graph<F: Float = f32> {
+    pub nodes: Vec<Node>,
+    /// Every layer is used by exactly one node
+    pub layers: Vec<LayerKind<F>>,
+    /// Shape of every input
+    pub inputs: Vec<Vec<usize>>,
+    /// The last node added, unless changed with `set_output`
+    pub output: usize,
+    /// Shape of one sample of every node, known as soon as it is added
+    shapes: Vec<Vec<usize>>,
+    /// When the output is an activation its error comes from `Loss::output_error`
+    #[serde(default)]
+    pub loss: LossFunction,
+    #[serde(default)]
+    optimizer: OptimizerFunction,
+    /// What the optimizer remembers of every parameter of every layer
+    #[serde(default)]
+    states: Vec<Vec<OptimizerState<IxDyn, F>>>,
+    #[serde(default)]
+    pub regularization: Regularization,
+    #[serde(default)]
+    pub clipping: Clipping,
+    /// Given to every layer, change it with `set_quantization`
+    #[serde(default)]
+    quantization: Option<FakeQuantization>,
+    /// The weights that were pruned stay at 0, `None` for a parameter that was never pruned
+    #[serde(default)]
+    pub(super) masks: Vec<Vec<Option<ArrayD<bool>>>>,
+    /// Dropout and batch statistics stay off in a loaded model until it is trained in `Mode::Train` again
+    #[serde(skip, default = "loaded_mode")]
+    mode: Mode,
+    #[serde(default)]
+    step: i32,
+    #[serde(default)]
+    seed: u64,
+    #[serde(skip)]
+    rng: Option<StdRng>
+}
+
+fn loaded_mode() -> Mode {
+    Mode::Eval
+}
+
+/// Adds `value` to the error of a node that feeds more than one other
+fn accumulate<F: Float>(errors: &mut [Option<ArrayD<F>>], node: usize, value: ArrayD<F>) {
+    match &mut errors[node] {
+        Some(error) => *error += &value,
+        empty => *empty = Some(value)
+    }
+}
+
+impl<F: Float> Graph<F> {
+    /// Empty graph, add inputs and layers with the builder methods
+    pub fn new() -> Self {
+        Self::with_seed(rand::random())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            nodes: Vec::new(),
+            layers: Vec::new(),
+            inputs: Vec::new(),
+            output: 0,
+            shapes: Vec::new(),
+            loss: LossFunction::default(),
+            optimizer: OptimizerFunction::default(),
+            states: Vec::new(),
+            regularization: Regularization::default(),
+            clipping: Clipping::default(),
+            quantization: None,
+            masks: Vec::new(),
+            mode: Mode::default(),
+            step: 0,
+            seed,
+            rng: Some(StdRng::seed_from_u64(seed))
+        }
+    }
+
+    /// Nodes can only be fed by nodes added before them, so the nodes are always in order
+    fn push(&mut self, node: Node) -> usize {
+        assert!(node.inputs().iter().all(|&input| input < self.nodes.len()), "Nodes can only be fed by nodes added before them");
+
+        let shape = match &node {
+            Node::Input(input) => self.inputs[*input].clone(),
+            Node::Layer { layer, input } => self.layers[*layer].output_shape(&self.shapes[*input]),
+            Node::Add(nodes) => self.shapes[nodes[0]].clone(),
+            Node::Concat(nodes) => {
+                let mut shape = self.shapes[nodes[0]].clone();
+                *shape.last_mut().expect("Empty shape") = nodes.iter().map(|&node| self.shapes[node][shape.len() - 1]).sum();
+
+                shape
+            }
+        };
+
+        self.shapes.push(shape);
+        self.nodes.push(node);
+        self.output = self.nodes.len() - 1;
+
+        self.output
+    }
+
+    /// New input of the model, returns its node
+    pub fn input(&mut self, shape: &[usize]) -> usize {
+        self.inputs.push(shape.to_vec());
+
+        self.push(Node::Input(self.inputs.len() - 1))
+    }
+
+    /// Feeds `input` to a new layer, returns its node
+    pub fn layer(&mut self, mut layer: LayerKind<F>, input: usize) -> usize {
+        layer.set_quantization(self.quantization);
+        self.layers.push(layer);
+
+        self.push(Node::Layer { layer: self.layers.len() - 1, input })
+    }
+
+    /// Fully connected layer, the biases start at 0
+    pub fn dense(&mut self, input: usize, size: usize, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert!(matches!(inputs.len(), 1 | 2), "Dense layers need flat inputs or sequences of them, got {inputs:?}");
+
+        let dense = Dense::new(inputs[inputs.len() - 1], size, initializer, Initializer::Zeros, self.rng());
+
+        self.layer(LayerKind::Dense(dense), input)
+    }
+
+    /// A softmax only makes sense as a probability distribution, it switches the loss to cross entropy
+    pub fn activation(&mut self, input: usize, activation: ActivationFunction) -> usize {
+        if activation == ActivationFunction::Softmax {
+            self.loss = LossFunction::CategoricalCrossEntropy;
+        }
+
+        self.layer(LayerKind::Activation(activation), input)
+    }
+
+    pub fn dropout(&mut self, input: usize, rate: f32) -> usize {
+        self.layer(LayerKind::Dropout(Dropout::new(rate)), input)
+    }
+
+    /// Nothing is added for `Normalization::None`, the input is returned
+    pub fn norm(&mut self, input: usize, norm: Normalization) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 1, "Normalization needs flat inputs, got {inputs:?}");
+
+        match norm.build(inputs[0]) {
+            Some(norm) => self.layer(LayerKind::Norm(norm), input),
+            None => input
+        }
+    }
+
+    pub fn reshape(&mut self, input: usize, shape: &[usize]) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.iter().product::<usize>(), shape.iter().product::<usize>(), "Can't reshape {inputs:?} to {shape:?}");
+
+        self.layer(LayerKind::Reshape(Reshape::new(shape)), input)
+    }
+
+    /// Flattens (channels, height, width) samples before dense layers
+    pub fn flatten(&mut self, input: usize) -> usize {
+        let reshape = Reshape::flatten(&self.shape(input));
+
+        self.layer(LayerKind::Reshape(reshape), input)
+    }
+
+    /// Convolution with `channels` kernels of size `kernel`, the biases start at 0
+    pub fn conv2d(&mut self, input: usize, channels: usize, kernel: (usize, usize), stride: usize, padding: usize, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 3, "Convolutions need (channels, height, width) inputs, got {inputs:?}");
+
+        let conv = Conv2D::new(inputs[0], channels, kernel, stride, padding, initializer, self.rng());
+
+        self.layer(LayerKind::Conv2D(conv), input)
+    }
+
+    pub fn max_pool(&mut self, input: usize, size: usize, stride: usize) -> usize {
+        self.layer(LayerKind::Pool(Pool::new(PoolKind::Max, size, stride)), input)
+    }
+
+    pub fn avg_pool(&mut self, input: usize, size: usize, stride: usize) -> usize {
+        self.layer(LayerKind::Pool(Pool::new(PoolKind::Average, size, stride)), input)
+    }
+
+    /// Recurrent layer of `size` hidden neurons over (time, features) inputs,
+    /// outputting every step when `sequences` is set and only the last one otherwise
+    pub fn recurrent(&mut self, input: usize, cell: Cell, size: usize, sequences: bool, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 2, "Recurrent layers need (time, features) inputs, got {inputs:?}");
+
+        let recurrent = Recurrent::new(cell, inputs[1], size, sequences, initializer, self.rng());
+
+        self.layer(LayerKind::Recurrent(recurrent), input)
+    }
+
+    /// Multi-head self-attention over (time, features) inputs, a digit reshaped to (28, 28) is a sequence of its rows
+    pub fn attention(&mut self, input: usize, heads: usize, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 2, "Attention needs (time, features) inputs, got {inputs:?}");
+
+        let attention = Attention::new(inputs[1], heads, initializer, self.rng());
+
+        self.layer(LayerKind::Attention(Box::new(attention)), input)
+    }
+
+    /// Learned embedding of the position of every step of (time, features) inputs
+    pub fn positional(&mut self, input: usize, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 2, "Positional embeddings need (time, features) inputs, got {inputs:?}");
+
+        let positional = Positional::new(inputs[0], inputs[1], initializer, self.rng());
+
+        self.layer(LayerKind::Positional(positional), input)
+    }
+
+    /// Fixed sinusoidal embedding of the position of every step of (time, features) inputs
+    pub fn sinusoidal(&mut self, input: usize) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 2, "Positional embeddings need (time, features) inputs, got {inputs:?}");
+
+        self.layer(LayerKind::Positional(Positional::sinusoidal(inputs[0], inputs[1])), input)
+    }
+
+    /// Transformer encoder block of `heads` attention heads and a feed forward stage of `hidden` neurons
+    pub fn encoder(&mut self, input: usize, heads: usize, hidden: usize, activation: ActivationFunction) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 2, "Encoders need (time, features) inputs, got {inputs:?}");
+
+        let encoder = Encoder::new(inputs[1], heads, hidden, activation, self.rng());
+
+        self.layer(LayerKind::Encoder(Box::new(encoder)), input)
+    }
+
+    /// Embeds every `size` x `size` patch of (channels, height, width) inputs into `features`, giving (patches, features) sequences
+    pub fn patch_embedding(&mut self, input: usize, size: usize, features: usize, initializer: Initializer) -> usize {
+        let inputs = self.shape(input);
+        assert_eq!(inputs.len(), 3, "Patch embeddings need (channels, height, width) inputs, got {inputs:?}");
+
+        let embedding = PatchEmbedding::new(inputs[0], size, features, initializer, self.rng());
+
+        self.layer(LayerKind::PatchEmbedding(embedding), input)
+    }
+
+    /// Rounds the values going through like `FakeQuantization` once the model is quantized with `set_quantization`,
+    /// nothing changes before
+    pub fn quantize(&mut self, input: usize) -> usize {
+        self.layer(LayerKind::Quantize(Quantize::new(self.quantization)), input)
+    }
+
+    pub fn add(&mut self, nodes: &[usize]) -> usize {
+        assert!(!nodes.is_empty(), "Nothing to add");
+
+        let shape = self.shape(nodes[0]);
+        assert!(nodes.iter().all(|&node| self.shape(node) == shape), "Added nodes not of same shape!");
+
+        self.push(Node::Add(nodes.to_vec()))
+    }
+
+    pub fn concat(&mut self, nodes: &[usize]) -> usize {
+        assert!(!nodes.is_empty(), "Nothing to concatenate");
+
+        let shape = self.shape(nodes[0]);
+        assert!(nodes.iter().all(|&node| self.shape(node).len() == shape.len()), "Concatenated nodes not of same dimensions!");
+        assert!(nodes.iter().all(|&node| self.shape(node)[..shape.len() - 1] == shape[..shape.len() - 1]), "Concatenated nodes differ before their last axis");
+
+        self.push(Node::Concat(nodes.to_vec()))
+    }
+
+    /// Adds `input` to what `block` builds on top of it, returns the sum
+    pub fn residual(&mut self, input: usize, block: impl FnOnce(&mut Self, usize) -> usize) -> usize {
+        let output = block(self, input);
+
+        self.add(&[input, output])
+    }
+
+    pub fn set_output(&mut self, node: usize) {
+        assert!(node < self.nodes.len(), "No node {node}");
+
+        self.output = node;
+    }
+
+    pub fn with_optimizer(mut self, optimizer: OptimizerFunction) -> Self {
+        self.set_optimizer(optimizer);
+        self
+    }
+
+    pub fn with_regularization(mut self, regularization: Regularization) -> Self {
+        self.regularization = regularization;
+        self
+    }
+
+    pub fn with_clipping(mut self, clipping: Clipping) -> Self {
+        self.clipping = clipping;
+        self
+    }
+
+    pub fn with_quantization(mut self, quantization: FakeQuantization) -> Self {
+        self.set_quantization(Some(quantization));
+        self
+    }
+
+    /// Shape of one sample of a node
+    pub fn shape(&self, node: usize) -> Vec<usize> {
+        self.shapes[node].clone()
+    }
+
+    pub fn output_shape(&self) -> Vec<usize> {
+        self.shape(self.output)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        let seed = self.seed;
+
+        self.rng.get_or_insert_with(|| StdRng::seed_from_u64(seed))
+    }
+
+    /// Changes the optimizer, forgetting everything the previous one knew
+    pub fn set_optimizer(&mut self, optimizer: OptimizerFunction) {
+        self.optimizer = optimizer;
+        self.step = 0;
+        self.states.clear();
+    }
+
+    pub fn optimizer(&self) -> OptimizerFunction {
+        self.optimizer
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn quantization(&self) -> Option<FakeQuantization> {
+        self.quantization
+    }
+
+    /// Quantization-aware training from now on, or back to float with `None`.
+    /// Dense and convolution layers round their weights, `Quantize` layers the values going through them.
+    pub fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.quantization = quantization;
+
+        for layer in self.layers.iter_mut() {
+            layer.set_quantization(quantization);
+        }
+    }
+
+    /// Flat samples, every input after the other, to one batch per input in its shape
+    pub(super) fn split(&self, inputs: ArrayD<F>) -> Vec<ArrayD<F>> {
+        let (samples, len) = (inputs.shape()[0], inputs.len());
+        let inputs = inputs.into_shape((samples, len / samples.max(1))).expect("Flat inputs");
+        let mut start = 0;
+
+        let split: Vec<ArrayD<F>> = self.inputs.iter().map(|shape| {
+            let len: usize = shape.iter().product();
+            let mut dim = vec![samples];
+            dim.extend_from_slice(shape);
+
+            let input = inputs.slice(s![.., start..start + len]).to_owned().into_shape(IxDyn(&dim)).expect("Input of the right size");
+            start += len;
+
+            input
+        }).collect();
+
+        assert_eq!(start, inputs.ncols(), "Input layers not of same size!");
+
+        split
+    }
+
+    /// Values of every node in the order they were added, `feed` goes through the layers
+    fn propagate(&self, inputs: Vec<ArrayD<F>>, mut feed: impl FnMut(usize, ArrayD<F>) -> ArrayD<F>) -> Vec<Option<ArrayD<F>>> {
+        let mut values: Vec<Option<ArrayD<F>>> = vec![None; self.nodes.len()];
+
+        fn value<F: Float>(values: &[Option<ArrayD<F>>], node: usize) -> ArrayViewD<'_, F> {
+            values[node].as_ref().expect("Node before its inputs").view()
+        }
+
+        for node in 0..self.nodes.len() {
+            values[node] = Some(match &self.nodes[node] {
+                Node::Input(input) => inputs[*input].clone(),
+                Node::Layer { layer, input } => feed(*layer, value(&values, *input).to_owned()),
+                Node::Add(nodes) => nodes[1..].iter().fold(value(&values, nodes[0]).to_owned(), |total, &node| total + value(&values, node)),
+                Node::Concat(nodes) => {
+                    let joined: Vec<ArrayViewD<F>> = nodes.iter().map(|&node| value(&values, node)).collect();
+                    let axis = Axis(joined[0].ndim() - 1);
+
+                    concatenate(axis, &joined).expect("Concatenated nodes of same shape")
+                }
+            });
+        }
+
+        values
+    }
+
+    /// Feeds a batch split to the inputs through the graph from the states in the workspace,
+    /// remembering what backpropagation needs. Returns the value of every node.
+    fn forward(&self, workspace: &mut Workspace<F>, inputs: Vec<ArrayD<F>>, rng: &mut StdRng) -> Vec<Option<ArrayD<F>>> {
+        let Workspace { caches, states, .. } = workspace;
+
+        self.propagate(inputs, |layer, value| self.layers[layer].forward_state(value, &mut caches[layer], &mut states[layer], self.mode, rng))
+    }
+
+    /// Output for a batch split to the inputs, recurrent layers start from `states` and leave their last state in it
+    pub(super) fn evaluate_states(&self, inputs: Vec<ArrayD<F>>, states: &mut [StateKind<F>]) -> ArrayD<F> {
+        let mut values = self.propagate(inputs, |layer, value| self.layers[layer].evaluate_state(value, &mut states[layer]));
+
+        values[self.output].take().expect("Output never computed")
+    }
+
+    /// Output of every input, one sample per row
+    /// No samples give no outputs, in the shape of the output
+    pub fn predict_batch(&self, inputs: ArrayD<F>) -> ArrayD<F> {
+        if inputs.shape()[0] == 0 {
+            let mut dim = vec![0];
+            dim.extend(self.output_shape());
+
+            return ArrayD::zeros(IxDyn(&dim))
+        }
+
+        let inputs = self.split(inputs);
+
+        let mut values = self.propagate(inputs, |layer, value| self.layers[layer].evaluate(value));
+
+        values[self.output].take().expect("Output never computed")
+    }
+
+    pub fn predict(&self, input: &Input<F>) -> Output<F> {
+        let inputs = input.view().insert_axis(Axis(0)).to_owned().into_dyn();
+
+        self.predict_batch(inputs).iter().copied().collect()
+    }
+
+    /// The output activation, when the loss is folded into it
+    fn output_activation(&self) -> Option<(ActivationFunction, usize, usize)> {
+        match self.nodes[self.output] {
+            Node::Layer { layer, input } => match self.layers[layer] {
+                LayerKind::Activation(activation) => Some((activation, layer, input)),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    /// Sums the gradients of the batch that was last fed forward into the workspace
+    fn backpropagete(&self, workspace: &mut Workspace<F>, outputs: &ArrayD<F>, activated: ArrayD<F>) {
+        let mut errors: Vec<Option<ArrayD<F>>> = vec![None; self.nodes.len()];
+
+        // an activated output is folded into the loss, so that softmax and cross entropy fuse
+        match self.output_activation() {
+            Some((activation, layer, input)) => {
+                let CacheKind::Activation((unscaled, _)) = &workspace.caches[layer] else { panic!("Backward before forward") };
+                errors[input] = Some(self.loss.output_error(activation, unscaled, &activated, outputs));
+            },
+            None => errors[self.output] = Some(self.loss.derivate(&activated, outputs))
+        }
+
+        for node in (0..self.nodes.len()).rev() {
+            let Some(error) = errors[node].take() else { continue };
+
+            match &self.nodes[node] {
+                Node::Input(_) => {},
+                Node::Layer { layer, input } => {
+                    let error = self.layers[*layer].backward(error, &workspace.caches[*layer], &mut workspace.gradients[*layer]);
+                    accumulate(&mut errors, *input, error);
+                },
+                Node::Add(nodes) => {
+                    for &input in nodes {
+                        accumulate(&mut errors, input, error.clone());
+                    }
+                },
+                Node::Concat(nodes) => {
+                    let axis = Axis(error.ndim() - 1);
+                    let mut start = 0;
+
+                    for &input in nodes {
+                        let len = self.shapes[input][self.shapes[input].len() - 1];
+                        accumulate(&mut errors, input, error.slice_axis(axis, (start..start + len).into()).to_owned());
+                        start += len;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resizes the optimizer states and pruning masks to the parameters, they are created lazily
+    /// so that changing the optimizer or adding layers just starts them from scratch
+    pub(super) fn fit(&mut self) {
+        self.states.resize_with(self.layers.len(), Vec::new);
+        self.masks.resize_with(self.layers.len(), Vec::new);
+
+        for ((layer, states), masks) in self.layers.iter().zip(self.states.iter_mut()).zip(self.masks.iter_mut()) {
+            let parameters = layer.parameters().len();
+
+            states.resize_with(parameters, OptimizerState::default);
+            masks.resize(parameters, None);
+        }
+    }
+
+    /// The gradients of every layer are summed over the batch in the first workspace, the optimizer gets their average.
+    /// The statistics layers keep track of come from the caches of all the workspaces.
+    /// Nothing is changed when the gradients or the updated parameters aren't finite.
+    pub(super) fn apply_gradient(&mut self, workspaces: &[Workspace<F>], rate: F, batch_size: usize) -> Result<(), NNError> {
+        let step = self.step + 1;
+        let gradients = &workspaces[0].gradients;
+
+        if let Some(layer) = workspaces[0].non_finite() {
+            return Err(NNError::NonFiniteGradient { layer, step })
+        }
+
+        let scale = F::one() / F::of(batch_size as f64);
+        let scale = scale * self.clipping.scale(workspaces[0].norm() * scale);
+
+        self.fit();
+        let (optimizer, regularization, clipping) = (self.optimizer, self.regularization, self.clipping);
+
+        // the gradient the optimizer is given for one parameter
+        let prepare = |parameter: &ArrayViewD<'_, F>, gradient: &ArrayD<F>, kind: ParameterKind, mask: &Option<ArrayD<bool>>| {
+            let mut gradient = gradient * scale;
+            clipping.clip(&mut gradient);
+
+            if regularization.applies(kind) {
+                regularization.add_gradient(parameter, &mut gradient);
+            }
+
+            if let Some(mask) = mask {
+                zero_pruned(&mut gradient, mask);
+            }
+
+            gradient
+        };
+
+        // every update is checked before any is written, so that a failed step leaves the model as it was
+        for (i, (((layer, gradients), states), masks)) in self.layers.iter().zip(gradients).zip(self.states.iter()).zip(self.masks.iter()).enumerate() {
+            for ((((parameter, gradient), state), mask), kind) in layer.parameters().into_iter().zip(gradients).zip(states).zip(masks).zip(layer.kinds()) {
+                let gradient = prepare(&parameter, gradient, kind, mask);
+
+                if !optimizer.finite(parameter, &gradient, state, rate, step) {
+                    return Err(NNError::NonFiniteParameter { layer: i, step })
+                }
+            }
+        }
+
+        for (((layer, gradients), states), masks) in self.layers.iter_mut().zip(gradients).zip(self.states.iter_mut()).zip(self.masks.iter()) {
+            let kinds = layer.kinds();
+
+            for ((((mut parameter, gradient), state), mask), kind) in layer.parameters_mut().into_iter().zip(gradients).zip(states.iter_mut()).zip(masks).zip(kinds) {
+                let gradient = prepare(&parameter.view(), gradient, kind, mask);
+
+                optimizer.update(parameter.view_mut(), &gradient, state, rate, step);
+
+                // momentum would bring pruned weights back
+                if let Some(mask) = mask {
+                    zero_pruned(&mut parameter, mask);
+                }
+            }
+        }
+
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let caches: Vec<&CacheKind<F>> = workspaces.iter().map(|workspace| &workspace.caches[i]).collect();
+
+            layer.track(&caches);
+        }
+
+        self.step = step;
+
+        Ok(())
+    }
+
+    /// Allocates a new workspace, reuse one with `train_batch` to avoid it
+    pub fn train_samples(&mut self, samples: &[Sample<F>], rate: F) -> Result<(), NNError> {
+        let mut workspace = Workspace::new(self);
+        let (inputs, outputs) = batch(samples);
+
+        self.train_batch(&mut workspace, inputs.into_dyn(), &outputs.into_dyn(), rate)
+    }
+
+    /// Trains on a whole mini-batch at once, flat inputs and outputs with one sample per row.
+    /// An empty batch changes nothing.
+    pub fn train_batch(&mut self, workspace: &mut Workspace<F>, inputs: ArrayD<F>, outputs: &ArrayD<F>, rate: F) -> Result<(), NNError> {
+        let batch_size = inputs.shape()[0];
+
+        if batch_size == 0 {
+            return Ok(())
+        }
+
+        workspace.clear_states();
+
+        self.train_split(workspace, self.split(inputs), outputs, rate, batch_size)
+    }
+
+    /// Allocates new workspaces, reuse them with `train_batch_parallel` to avoid it
+    pub fn train_samples_parallel(&mut self, samples: &[Sample<F>], rate: F, threads: usize) -> Result<(), NNError> {
+        let mut workspaces: Vec<Workspace<F>> = (0..threads.max(1)).map(|_| Workspace::new(self)).collect();
+        let (inputs, outputs) = batch(samples);
+
+        self.train_batch_parallel(&mut workspaces, inputs.into_dyn(), &outputs.into_dyn(), rate)
+    }
+
+    /// Like `train_batch`, the batch is split in one shard per workspace, each fed on its own thread.
+    /// The gradients of the shards are summed before being applied, so the update is the same up to rounding,
+    /// except for what looks at the whole batch: batch normalization and fake quantization only see their shard.
+    pub fn train_batch_parallel(&mut self, workspaces: &mut [Workspace<F>], inputs: ArrayD<F>, outputs: &ArrayD<F>, rate: F) -> Result<(), NNError> {
+        assert!(!workspaces.is_empty(), "At least one workspace is needed");
+
+        let batch_size = inputs.shape()[0];
+
+        // no shard to reduce
+        if batch_size == 0 {
+            return Ok(())
+        }
+
+        let shard_size = batch_size.div_ceil(workspaces.len());
+        let shards: Vec<(ArrayD<F>, ArrayD<F>)> = inputs.axis_chunks_iter(Axis(0), shard_size)
+            .zip(outputs.axis_chunks_iter(Axis(0), shard_size))
+            .map(|(inputs, outputs)| (inputs.to_owned(), outputs.to_owned()))
+            .collect();
+
+        // every shard gets its own rng, drawn in order so that a seed gives the same training
+        let seeds: Vec<u64> = shards.iter().map(|_| self.rng().gen()).collect();
+        let used = shards.len();
+        let graph = &*self;
+
+        std::thread::scope(|scope| {
+            for ((workspace, (inputs, outputs)), seed) in workspaces.iter_mut().zip(shards).zip(seeds) {
+                scope.spawn(move || {
+                    workspace.clear_states();
+                    graph.sum_gradients(workspace, graph.split(inputs), &outputs, &mut StdRng::seed_from_u64(seed));
+                });
+            }
+        });
+
+        let (total, shards) = workspaces.split_at_mut(1);
+
+        for workspace in shards[..used - 1].iter() {
+            total[0].add_gradient(workspace);
+        }
+
+        self.apply_gradient(&workspaces[..used], rate, batch_size)
+    }
+
+    /// One update from a batch already split to the inputs, `batch_size` is what the gradients are averaged over
+    pub(super) fn train_split(&mut self, workspace: &mut Workspace<F>, inputs: Vec<ArrayD<F>>, outputs: &ArrayD<F>, rate: F, batch_size: usize) -> Result<(), NNError> {
+        let mut rng = StdRng::seed_from_u64(self.rng().gen());
+
+        self.sum_gradients(workspace, inputs, outputs, &mut rng);
+
+        self.apply_gradient(std::slice::from_ref(workspace), rate, batch_size)
+    }
+
+    /// Feeds a batch already split to the inputs forward and back, the gradients of every layer are summed in the workspace
+    pub(super) fn sum_gradients(&self, workspace: &mut Workspace<F>, inputs: Vec<ArrayD<F>>, outputs: &ArrayD<F>, rng: &mut StdRng) {
+        workspace.clear_gradient();
+
+        // the outputs take the shape of the output, so that softmax and sequences keep their last axis
+        let mut values = self.forward(workspace, inputs, rng);
+        let activated = values[self.output].take().expect("Output never computed");
+        let outputs = outputs.to_shape(activated.raw_dim()).expect("Output layers not of same size!").into_owned();
+
+        self.backpropagete(workspace, &outputs, activated);
+    }
+
+    /// Summed loss of the values of every node
+    fn loss(&self, mut values: Vec<Option<ArrayD<F>>>, outputs: &ArrayD<F>) -> F {
+        // the unscaled values of an activated output give a stable loss
+        match self.output_activation() {
+            Some((activation, _, input)) => {
+                let unscaled = values[input].take().expect("Output never computed");
+                let outputs = outputs.to_shape(unscaled.raw_dim()).expect("Output layers not of same size!").into_owned();
+                let activated = activation.activate(&unscaled);
+
+                self.loss.output_loss(activation, &unscaled, &activated, &outputs)
+            },
+            None => {
+                let activated = values[self.output].take().expect("Output never computed");
+                let outputs = outputs.to_shape(activated.raw_dim()).expect("Output layers not of same size!").into_owned();
+
+                self.loss.loss(&activated, &outputs)
+            }
+        }
+    }
+
+    /// Summed loss of a batch already split to the inputs
+    pub(super) fn error(&self, inputs: Vec<ArrayD<F>>, outputs: &ArrayD<F>) -> F {
+        self.loss(self.propagate(inputs, |layer, value| self.layers[layer].evaluate(value)), outputs)
+    }
+
+    /// Summed loss of a batch already split to the inputs, fed like while training
+    pub(super) fn forward_error(&self, workspace: &mut Workspace<F>, inputs: Vec<ArrayD<F>>, outputs: &ArrayD<F>, rng: &mut StdRng) -> F {
+        let values = self.forward(workspace, inputs, rng);
+
+        self.loss(values, outputs)
+    }
+
+    /// Regularization penalty of the parameters
+    pub fn penalty(&self) -> F {
+        self.layers.iter().flat_map(|layer| layer.parameters().into_iter().zip(layer.kinds()))
+            .filter(|(_, kind)| self.regularization.applies(*kind))
+            .map(|(parameter, _)| self.regularization.penalty(&parameter))
+            .sum()
+    }
+
+    /// Average loss of the samples, plus the regularization penalty, 0 without samples
+    pub fn score(&self, samples: &[Sample<F>]) -> F {
+        if samples.is_empty() {
+            return F::zero()
+        }
+
+        let mut total = F::zero();
+
+        samples.chunks(super::SCORE_BATCH).for_each(|chunk| {
+            let (inputs, outputs) = batch(chunk);
+
+            total += self.error(self.split(inputs.into_dyn()), &outputs.into_dyn())
+        });
+
+        total / F::of(samples.len() as f64) + self.penalty()
+    }
+}
+
+impl<F: Float> Default for Graph<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array1;
+
+    use super::*;
+    use crate::nn::gradient_check;
+
+    /// Two inputs of 3 and 2 values, a residual block on the first one, both joined before the output
+    fn graph() -> Graph<f64> {
+        let mut graph = Graph::with_seed(5);
+        let first = graph.input(&[3]);
+        let second = graph.input(&[2]);
+
+        let residual = graph.residual(first, |graph, input| {
+            let hidden = graph.dense(input, 3, Initializer::GlorotUniform);
+
+            graph.activation(hidden, ActivationFunction::Tanh)
+        });
+        let second = graph.dense(second, 2, Initializer::GlorotUniform);
+        let joined = graph.concat(&[residual, second]);
+
+        let output = graph.dense(joined, 2, Initializer::GlorotUniform);
+        graph.activation(output, ActivationFunction::Sigmoid);
+
+        graph
+    }
+
+    fn samples() -> Vec<Sample<f64>> {
+        (0..6).map(|i| {
+            let x = i as f64 / 6.0;
+
+            Sample { input: Array1::from(vec![x, 1.0 - x, x * x, 0.5 - x, x / 2.0]), output: vec![x, 1.0 - x] }
+        }).collect()
+    }
+
+    #[test]
+    fn gradients_through_add_concat_and_two_inputs() {
+        let mut graph = graph();
+
+        for error in gradient_check(&mut graph, &samples(), 1e-5) {
+            assert!(error.max() < 1e-7, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let mut graph = graph().with_optimizer(OptimizerFunction::adam());
+        graph.train_samples(&samples(), 0.1).unwrap();
+
+        let json = serde_json::to_string(&graph).unwrap();
+        let mut loaded: Graph<f64> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!((&loaded.nodes, &loaded.inputs, loaded.output, loaded.loss), (&graph.nodes, &graph.inputs, graph.output, graph.loss));
+
+        // parsing may be off by the last bit of a float
+        let close = |a: &ArrayD<f64>, b: &ArrayD<f64>| a.shape() == b.shape() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-12);
+
+        let inputs = batch(&samples()).0.into_dyn();
+        assert!(close(&loaded.predict_batch(inputs.clone()), &graph.predict_batch(inputs)));
+
+        // the optimizer carries on from the same moments
+        graph.train_samples(&samples(), 0.1).unwrap();
+        loaded.train_samples(&samples(), 0.1).unwrap();
+
+        for (loaded, layer) in loaded.layers.iter().zip(&graph.layers) {
+            for (loaded, parameter) in loaded.parameters().iter().zip(layer.parameters()) {
+                assert!(close(&loaded.to_owned(), &parameter.to_owned()));
+            }
+        }
+    }
+
+    #[test]
+    fn loaded_models_are_evaluated() {
+        let graph = graph();
+        assert_eq!(graph.mode(), Mode::Train);
+
+        let loaded: Graph<f64> = serde_json::from_str(&serde_json::to_string(&graph).unwrap()).unwrap();
+        assert_eq!(loaded.mode(), Mode::Eval);
+    }
+
+    #[test]
+    #[should_panic(expected = "Concatenated nodes not of same dimensions!")]
+    fn concatenated_dimensions_are_compared_first() {
+        let mut graph: Graph<f64> = Graph::with_seed(0);
+        let flat = graph.input(&[3]);
+        let sequence = graph.input(&[2, 3]);
+
+        graph.concat(&[sequence, flat]);
+    }
+
+    #[test]
+    fn no_samples_score_0_and_predict_nothing() {
+        assert_eq!(graph().score(&[]), 0.0);
+        assert_eq!(graph().predict_batch(ArrayD::zeros(IxDyn(&[0, 5]))).shape(), [0, 2]);
+    }
+}
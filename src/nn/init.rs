@@ -0,0 +1,166 @@
+use serde::{Serialize, Deserialize};
+use rand::Rng;
+use ndarray::{Array1, Array2};
+
+use super::{Float, activation::ActivationFunction};
+
+/// How the weights and biases of a layer get their starting values,
+/// fan in is the size of the previous layer and fan out the size of the current one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Initializer {
+    /// Uniform between -a and a
+    Uniform(f32),
+    /// Glorot / Xavier, keeps the variance equal going forward and backward, made for sigmoid and tanh
+    GlorotUniform,
+    GlorotNormal,
+    /// He / Kaiming, doubles the variance of Glorot to make up for the half ReLU cuts off
+    HeUniform,
+    HeNormal,
+    /// Variance of 1 / fan in, made for self normalizing networks
+    LeCunUniform,
+    LeCunNormal,
+    /// Random orthonormal rows or columns scaled by a gain, only for weights
+    Orthogonal(f32),
+    Zeros,
+    Constant(f32)
+}
+
+impl Initializer {
+    /// Sensible default for the weights feeding into a layer activated by `activation`
+    pub fn for_activation(activation: ActivationFunction) -> Self {
+        match activation {
+            ActivationFunction::ReLU |
+            ActivationFunction::LeakyReLU(_) |
+            ActivationFunction::Elu |
+            ActivationFunction::Gelu |
+            ActivationFunction::Swish => Initializer::HeUniform,
+            _ => Initializer::GlorotUniform
+        }
+    }
+
+    /// Weights of shape (fan out, fan in), drawn in `f32` whatever the precision so that a seed gives the same values
+    pub fn weights<F: Float>(&self, fan_out: usize, fan_in: usize, rng: &mut impl Rng) -> Array2<F> {
+        let weights = match self {
+            Initializer::Orthogonal(gain) => orthogonal(fan_out, fan_in, *gain, rng),
+            _ => Array2::from_shape_simple_fn((fan_out, fan_in), || self.value(fan_in, fan_out, rng))
+        };
+
+        weights.mapv(|value| F::of(value.into()))
+    }
+
+    pub fn bias<F: Float>(&self, size: usize, fan_in: usize, rng: &mut impl Rng) -> Array1<F> {
+        assert!(!matches!(self, Initializer::Orthogonal(_)), "Biases can't be orthogonal");
+
+        Array1::from_shape_simple_fn(size, || F::of(self.value(fan_in, size, rng).into()))
+    }
+
+    fn value(&self, fan_in: usize, fan_out: usize, rng: &mut impl Rng) -> f32 {
+        let fan_in = fan_in.max(1) as f32;
+        let fan_out = fan_out.max(1) as f32;
+
+        match self {
+            Initializer::Uniform(a) => uniform(*a, rng),
+            // a uniform distribution between -a and a has a variance of a^2 / 3
+            Initializer::GlorotUniform => uniform((6.0 / (fan_in + fan_out)).sqrt(), rng),
+            Initializer::GlorotNormal => normal((2.0 / (fan_in + fan_out)).sqrt(), rng),
+            Initializer::HeUniform => uniform((6.0 / fan_in).sqrt(), rng),
+            Initializer::HeNormal => normal((2.0 / fan_in).sqrt(), rng),
+            Initializer::LeCunUniform => uniform((3.0 / fan_in).sqrt(), rng),
+            Initializer::LeCunNormal => normal((1.0 / fan_in).sqrt(), rng),
+            Initializer::Orthogonal(_) => unreachable!("Orthogonal depends on the whole matrix"),
+            Initializer::Zeros => 0.0,
+            Initializer::Constant(value) => *value
+        }
+    }
+}
+
+fn uniform(limit: f32, rng: &mut impl Rng) -> f32 {
+    if limit == 0.0 {
+        return 0.0
+    }
+
+    rng.gen_range(-limit..limit)
+}
+
+/// Box-Muller transform, rand only has a uniform distribution
+fn normal(deviation: f32, rng: &mut impl Rng) -> f32 {
+    let u1: f32 = 1.0 - rng.gen::<f32>();
+    let u2: f32 = rng.gen();
+
+    deviation * (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+/// Gram-Schmidt on a random normal matrix, along its shorter side
+fn orthogonal(rows: usize, cols: usize, gain: f32, rng: &mut impl Rng) -> Array2<f32> {
+    let transposed = rows < cols;
+    let (rows, cols) = if transposed { (cols, rows) } else { (rows, cols) };
+
+    // orthonormalize the columns of a tall matrix
+    let mut matrix = Array2::from_shape_simple_fn((rows, cols), || normal(1.0, rng));
+
+    for col in 0..cols {
+        for prev in 0..col {
+            let projection = matrix.column(col).dot(&matrix.column(prev));
+            let prev_column = matrix.column(prev).to_owned();
+
+            matrix.column_mut(col).scaled_add(-projection, &prev_column);
+        }
+
+        let norm = matrix.column(col).dot(&matrix.column(col)).sqrt();
+        matrix.column_mut(col).mapv_inplace(|value| value / norm);
+    }
+
+    matrix *= gain;
+
+    if transposed {
+        // keep the standard layout like every other matrix of the network
+        matrix.reversed_axes().as_standard_layout().to_owned()
+    } else {
+        matrix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+    use ndarray::Array2;
+
+    use super::*;
+
+    #[test]
+    fn variances_of_glorot_and_he() {
+        let (fan_out, fan_in) = (200, 300);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let expectations = [
+            (Initializer::GlorotUniform, 2.0 / (fan_in + fan_out) as f64),
+            (Initializer::GlorotNormal, 2.0 / (fan_in + fan_out) as f64),
+            (Initializer::HeUniform, 2.0 / fan_in as f64),
+            (Initializer::HeNormal, 2.0 / fan_in as f64)
+        ];
+
+        for (initializer, expected) in expectations {
+            let weights: Array2<f64> = initializer.weights(fan_out, fan_in, &mut rng);
+            let variance = weights.var(0.0);
+
+            // 60 000 draws, the variance is within a few percent
+            assert!((variance / expected - 1.0).abs() < 0.03, "{initializer:?}: {variance} != {expected}");
+            assert!(weights.mean().unwrap().abs() < 0.02 * expected.sqrt(), "{initializer:?} not centered");
+        }
+    }
+
+    #[test]
+    fn orthogonal_along_the_shorter_side() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for (fan_out, fan_in) in [(8, 5), (5, 8), (6, 6)] {
+            let weights: Array2<f64> = Initializer::Orthogonal(2.0).weights(fan_out, fan_in, &mut rng);
+
+            // the columns of a tall matrix are orthonormal, the rows of a wide one
+            let product = if fan_out >= fan_in { weights.t().dot(&weights) } else { weights.dot(&weights.t()) };
+            let identity = Array2::<f64>::eye(fan_out.min(fan_in)) * 4.0;
+
+            assert!((product - &identity).iter().all(|difference| difference.abs() < 1e-5), "{fan_out}x{fan_in} not orthogonal");
+        }
+    }
+}
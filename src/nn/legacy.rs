@@ -0,0 +1,88 @@
+use serde::Deserialize;
+use ndarray::{Array1, Array2};
+
+use super::{NN, LayerConfig, ActivationFunction, Mode};
+use super::sequential::LayerKind;
+
+/// Layout of the networks saved before their layers were configurable, like `saved_nn.json` and `95.json`:
+/// sigmoid layers fully connected one after the other
+#[derive(Deserialize)]
+struct Legacy {
+    /// The input layer first, its biases are never used
+    layers: Vec<LegacyLayer>,
+    /// Between every two layers
+    connections: Vec<LegacyConnections>
+}
+
+#[derive(Deserialize)]
+struct LegacyLayer {
+    bias_b: Array1<f32>
+}
+
+#[derive(Deserialize)]
+struct LegacyConnections {
+    /// (current, previous) like the weights of a dense layer
+    value_w: Array2<f32>
+}
+
+impl NN {
+    /// Loads a network saved with `serde_json`, or one saved in the layout from before the layers were configurable
+    pub fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
+        serde_json::from_str(json).or_else(|error| match serde_json::from_str::<Legacy>(json) {
+            Ok(legacy) => Ok(legacy.into_nn()),
+            // the error of the current layout is the one worth reporting
+            Err(_) => Err(error)
+        })
+    }
+}
+
+impl Legacy {
+    fn into_nn(self) -> Box<NN> {
+        assert!(!self.connections.is_empty() && self.layers.len() == self.connections.len() + 1, "Not a layer more than connections");
+
+        let configs: Vec<LayerConfig> = self.connections.iter().map(|connections| LayerConfig::new(connections.value_w.nrows(), ActivationFunction::Sigmoid)).collect();
+        let mut nn = NN::from_seed(self.connections[0].value_w.ncols(), &configs, 0);
+
+        let dense = nn.layers.iter_mut().filter_map(|layer| match layer {
+            LayerKind::Dense(dense) => Some(dense),
+            _ => None
+        });
+
+        for ((dense, connections), layer) in dense.zip(self.connections).zip(self.layers.into_iter().skip(1)) {
+            dense.value_w = connections.value_w;
+            dense.bias_b = layer.bias_b;
+        }
+
+        // like any other loaded model
+        nn.set_mode(Mode::Eval);
+
+        nn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn legacy_networks_compute_like_they_did() {
+        let json = r#"{
+            "layers": [
+                {"value_a": {"v": 1, "dim": [2], "data": [0.0, 0.0]}, "bias_b": {"v": 1, "dim": [2], "data": [0.3, -0.2]}},
+                {"value_a": {"v": 1, "dim": [1], "data": [0.0]}, "bias_b": {"v": 1, "dim": [1], "data": [0.5]}}
+            ],
+            "connections": [
+                {"value_w": {"v": 1, "dim": [1, 2], "data": [1.0, -2.0]}}
+            ]
+        }"#;
+
+        let nn = NN::from_json(json).unwrap();
+        let expected = 1.0 / (1.0 + (-(1.0 * 0.25 - 2.0 * 0.75 + 0.5f32)).exp());
+
+        assert!((nn.predict(&array![0.25, 0.75])[0] - expected).abs() < 1e-6);
+        assert_eq!(nn.mode(), Mode::Eval);
+        assert!(NN::from_json("{}").is_err());
+    }
+}
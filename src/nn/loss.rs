@@ -0,0 +1,229 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, Axis, Dimension, Zip};
+use num_traits::clamp;
+
+use super::Float;
+use super::activation::{ActivationFunction, log_sum_exp};
+
+/// Measures how far the output layer is from the expected output,
+/// samples are along the first axis and the outputs along the last one
+pub trait Loss {
+    /// Loss of every sample summed over the samples, the errors are averaged over the outputs
+    /// and the cross-entropies summed over them like a log-loss
+    fn loss<F: Float, D: Dimension>(&self, activated: &Array<F, D>, target: &Array<F, D>) -> F;
+
+    /// Derivative of the loss with respect to every activated output
+    fn derivate<F: Float, D: Dimension>(&self, activated: &Array<F, D>, target: &Array<F, D>) -> Array<F, D>;
+
+    /// Same reduction as `loss`, can be overridden when the pairing with the activation
+    /// allows for a more stable formula
+    fn output_loss<F: Float, D: Dimension>(&self, activation: ActivationFunction, unscaled: &Array<F, D>, activated: &Array<F, D>, target: &Array<F, D>) -> F {
+        let _ = (activation, unscaled);
+        self.loss(activated, target)
+    }
+
+    /// Error of the unscaled output layer, the starting point of backpropagation
+    fn output_error<F: Float, D: Dimension>(&self, activation: ActivationFunction, unscaled: &Array<F, D>, activated: &Array<F, D>, target: &Array<F, D>) -> Array<F, D> {
+        activation.derivate(unscaled, activated, &self.derivate(activated, target))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LossFunction {
+    /// Mean squared error
+    #[default]
+    #[serde(alias = "MSE")]
+    Mse,
+    /// Mean absolute error
+    #[serde(alias = "MAE")]
+    Mae,
+    /// Squared error below delta, absolute error above it
+    Huber(f32),
+    /// Each output is an independent probability, pairs with Sigmoid
+    BinaryCrossEntropy,
+    /// Outputs are a single probability distribution, pairs with Softmax
+    CategoricalCrossEntropy,
+    /// Cross-entropy down weighting the already well classified outputs by (1 - a)^gamma
+    Focal(f32)
+}
+
+
+// keeps the logarithms of probabilities finite
+const EPSILON: f64 = 1e-7;
+
+fn clamp_probability<F: Float>(value: F) -> F {
+    clamp(value, F::of(EPSILON), F::one() - F::of(EPSILON))
+}
+
+fn softplus<F: Float>(value: F) -> F {
+    value.max(F::zero()) + (-value.abs()).exp().ln_1p()
+}
+
+/// Amount of outputs of a single sample, the errors are averaged over them
+fn output_len<F: Float, D: Dimension>(values: &Array<F, D>) -> F {
+    F::of(values.len_of(Axis(values.ndim() - 1)) as f64)
+}
+
+impl Loss for LossFunction {
+    fn loss<F: Float, D: Dimension>(&self, activated: &Array<F, D>, target: &Array<F, D>) -> F {
+        assert_eq!(activated.shape(), target.shape(), "Output layers not of same size!");
+
+        let len = output_len(activated);
+        let (zero, one, two) = (F::zero(), F::one(), F::of(2.0));
+
+        match *self {
+            LossFunction::Mse => {
+                Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    total + (a - y) * (a - y)
+                }) / len
+            },
+            LossFunction::Mae => {
+                Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    total + (a - y).abs()
+                }) / len
+            },
+            LossFunction::Huber(delta) => {
+                let delta = F::of(delta.into());
+
+                Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    let diff = (a - y).abs();
+
+                    if diff <= delta {
+                        total + diff * diff / two
+                    } else {
+                        total + delta * (diff - delta / two)
+                    }
+                }) / len
+            },
+            LossFunction::BinaryCrossEntropy => {
+                -Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    let a = clamp_probability(a);
+                    total + y * a.ln() + (one - y) * (one - a).ln()
+                })
+            },
+            LossFunction::CategoricalCrossEntropy => {
+                -Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    total + y * clamp_probability(a).ln()
+                })
+            },
+            LossFunction::Focal(gamma) => {
+                let gamma = F::of(gamma.into());
+
+                -Zip::from(activated).and(target).fold(zero, |total, &a, &y| {
+                    let a = clamp_probability(a);
+                    total + y * (one - a).powf(gamma) * a.ln()
+                })
+            }
+        }
+    }
+
+    fn derivate<F: Float, D: Dimension>(&self, activated: &Array<F, D>, target: &Array<F, D>) -> Array<F, D> {
+        assert_eq!(activated.shape(), target.shape(), "Output layers not of same size!");
+
+        let len = output_len(activated);
+        let (one, two) = (F::one(), F::of(2.0));
+
+        match *self {
+            LossFunction::Mse => {
+                Zip::from(activated).and(target).map_collect(|&a, &y| two * (a - y) / len)
+            },
+            LossFunction::Mae => {
+                Zip::from(activated).and(target).map_collect(|&a, &y| {
+                    if a == y { F::zero() } else { (a - y).signum() / len }
+                })
+            },
+            LossFunction::Huber(delta) => {
+                let delta = F::of(delta.into());
+
+                Zip::from(activated).and(target).map_collect(|&a, &y| {
+                    clamp(a - y, -delta, delta) / len
+                })
+            },
+            LossFunction::BinaryCrossEntropy => {
+                Zip::from(activated).and(target).map_collect(|&a, &y| {
+                    let a = clamp_probability(a);
+                    (a - y) / (a * (one - a))
+                })
+            },
+            LossFunction::CategoricalCrossEntropy => {
+                Zip::from(activated).and(target).map_collect(|&a, &y| {
+                    -y / clamp_probability(a)
+                })
+            },
+            LossFunction::Focal(gamma) => {
+                let gamma = F::of(gamma.into());
+
+                Zip::from(activated).and(target).map_collect(|&a, &y| {
+                    let a = clamp_probability(a);
+                    let rest = one - a;
+
+                    -y * (rest.powf(gamma) / a - gamma * rest.powf(gamma - one) * a.ln())
+                })
+            }
+        }
+    }
+
+    fn output_loss<F: Float, D: Dimension>(&self, activation: ActivationFunction, unscaled: &Array<F, D>, activated: &Array<F, D>, target: &Array<F, D>) -> F {
+        match (self, activation) {
+            (LossFunction::CategoricalCrossEntropy, ActivationFunction::Softmax) => {
+                let axis = Axis(unscaled.ndim() - 1);
+
+                // ln(a) taken from the unscaled values to stay finite
+                -Zip::from(unscaled.lanes(axis)).and(target.lanes(axis)).fold(F::zero(), |total, unscaled, target| {
+                    let log_sum = log_sum_exp(unscaled);
+
+                    total + Zip::from(unscaled).and(target).fold(F::zero(), |total, &z, &y| {
+                        total + y * (z - log_sum)
+                    })
+                })
+            },
+            (LossFunction::BinaryCrossEntropy, ActivationFunction::Sigmoid) => {
+                // ln(sigmoid(z)) = -softplus(-z) and ln(1 - sigmoid(z)) = -softplus(z)
+                Zip::from(unscaled).and(target).fold(F::zero(), |total, &z, &y| {
+                    total + y * softplus(-z) + (F::one() - y) * softplus(z)
+                })
+            },
+            _ => self.loss(activated, target)
+        }
+    }
+
+    fn output_error<F: Float, D: Dimension>(&self, activation: ActivationFunction, unscaled: &Array<F, D>, activated: &Array<F, D>, target: &Array<F, D>) -> Array<F, D> {
+        match (self, activation) {
+            // the derivative of the activation cancels out, leaving only a - y
+            (LossFunction::CategoricalCrossEntropy, ActivationFunction::Softmax) |
+            (LossFunction::BinaryCrossEntropy, ActivationFunction::Sigmoid) => activated - target,
+            _ => activation.derivate(unscaled, activated, &self.derivate(activated, target))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn cross_entropy_is_the_log_loss_of_the_true_class() {
+        let activated = array![[0.7, 0.2, 0.1], [0.25, 0.25, 0.5]];
+        let target = array![[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        let expected = -(0.7f64.ln() + 0.5f64.ln());
+
+        let loss = LossFunction::CategoricalCrossEntropy;
+        assert!((loss.loss(&activated, &target) - expected).abs() < 1e-12);
+
+        // softmax gives back the probabilities from their logarithms
+        let unscaled = activated.mapv(f64::ln);
+        assert!((loss.output_loss(ActivationFunction::Softmax, &unscaled, &activated, &target) - expected).abs() < 1e-12);
+        assert_eq!(loss.output_error(ActivationFunction::Softmax, &unscaled, &activated, &target), &activated - &target);
+    }
+
+    #[test]
+    fn errors_are_averaged_over_the_outputs() {
+        let activated = array![[0.5, 1.0, 0.0, 2.0]];
+        let target = array![[0.0, 1.0, 1.0, 0.0]];
+
+        assert_eq!(LossFunction::Mse.loss(&activated, &target), (0.25 + 1.0 + 4.0) / 4.0);
+        assert_eq!(LossFunction::Mae.loss(&activated, &target), (0.5 + 1.0 + 2.0) / 4.0);
+    }
+}
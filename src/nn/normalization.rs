@@ -0,0 +1,228 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array1, Array2, ArrayD, ArrayViewD, ArrayViewMutD, Axis};
+
+use super::Float;
+
+/// Normalization of the unscaled values of a layer, before its activation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Normalizes every neuron over the batch, running statistics are kept for inference
+    Batch { momentum: f32, epsilon: f32 },
+    /// Normalizes every sample over its neurons, works the same for any batch size
+    Layer { epsilon: f32 }
+}
+
+impl Normalization {
+    pub fn batch() -> Self {
+        Self::Batch { momentum: 0.9, epsilon: 1e-5 }
+    }
+
+    pub fn layer() -> Self {
+        Self::Layer { epsilon: 1e-5 }
+    }
+
+    pub(super) fn build<F: Float>(&self, size: usize) -> Option<Norm<F>> {
+        match *self {
+            Self::None => None,
+            Self::Batch { momentum, epsilon } => Some(Norm::Batch(BatchNorm::new(size, momentum, epsilon))),
+            Self::Layer { epsilon } => Some(Norm::Layer(LayerNorm::new(size, epsilon)))
+        }
+    }
+}
+
+/// Learnable normalization stage of a layer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Norm<F: Float = f32> {
+    Batch(BatchNorm<F>),
+    Layer(LayerNorm<F>)
+}
+
+impl<F: Float> Norm<F> {
+    /// Normalizes while training, with the statistics of the batch when `batch` is set
+    pub(super) fn forward(&self, unscaled: &Array2<F>, batch: bool) -> (Array2<F>, NormCache<F>) {
+        match self {
+            Self::Batch(norm) => norm.forward(unscaled, batch),
+            Self::Layer(norm) => norm.forward(unscaled)
+        }
+    }
+
+    /// Normalizes for inference, never looking at the rest of the batch
+    pub(super) fn evaluate(&self, unscaled: &Array2<F>) -> Array2<F> {
+        self.forward(unscaled, false).0
+    }
+
+    /// Error of the unscaled values given the error of the normalized ones,
+    /// adding to the gradients of the gain and of the bias
+    pub(super) fn backward(&self, cache: &NormCache<F>, error: &Array2<F>, gradients: &mut [ArrayD<F>]) -> Array2<F> {
+        gradients[0] += &(error * &cache.normalized).sum_axis(Axis(0));
+        gradients[1] += &error.sum_axis(Axis(0));
+
+        match self {
+            Self::Batch(norm) => norm.backward(cache, error),
+            Self::Layer(norm) => norm.backward(cache, error)
+        }
+    }
+
+    /// Gain and bias
+    pub(super) fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        let (gamma, beta) = match self {
+            Self::Batch(norm) => (&norm.gamma, &norm.beta),
+            Self::Layer(norm) => (&norm.gamma, &norm.beta)
+        };
+
+        vec![gamma.view().into_dyn(), beta.view().into_dyn()]
+    }
+
+    pub(super) fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        let (gamma, beta) = match self {
+            Self::Batch(norm) => (&mut norm.gamma, &mut norm.beta),
+            Self::Layer(norm) => (&mut norm.gamma, &mut norm.beta)
+        };
+
+        vec![gamma.view_mut().into_dyn(), beta.view_mut().into_dyn()]
+    }
+
+    /// Updates the running statistics of batch normalization with those of the batches of the caches,
+    /// each weighted by its amount of samples
+    pub(super) fn track(&mut self, caches: &[&NormCache<F>]) {
+        if let Self::Batch(norm) = self {
+            norm.track(caches)
+        }
+    }
+}
+
+/// y = gamma * (z - mean) / sqrt(var + epsilon) + beta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchNorm<F: Float = f32> {
+    pub gamma: Array1<F>,
+    pub beta: Array1<F>,
+    /// Used instead of the statistics of the batch for inference
+    pub running_mean: Array1<F>,
+    pub running_var: Array1<F>,
+    /// How much of the running statistics is kept every step
+    pub momentum: f32,
+    pub epsilon: f32
+}
+
+impl<F: Float> BatchNorm<F> {
+    pub fn new(size: usize, momentum: f32, epsilon: f32) -> Self {
+        Self {
+            gamma: Array1::ones(size),
+            beta: Array1::zeros(size),
+            running_mean: Array1::zeros(size),
+            running_var: Array1::ones(size),
+            momentum,
+            epsilon
+        }
+    }
+
+    fn forward(&self, unscaled: &Array2<F>, batch: bool) -> (Array2<F>, NormCache<F>) {
+        let stats = batch.then(|| {
+            (unscaled.mean_axis(Axis(0)).expect("Empty batch"), unscaled.var_axis(Axis(0), F::zero()))
+        });
+
+        let (mean, var) = match &stats {
+            Some((mean, var)) => (mean, var),
+            None => (&self.running_mean, &self.running_var)
+        };
+
+        let inv_std = var.mapv(|var| F::one() / (var + F::of(self.epsilon.into())).sqrt());
+        let normalized = (unscaled - mean) * &inv_std;
+        let value = &normalized * &self.gamma + &self.beta;
+
+        (value, NormCache { normalized, inv_std, stats })
+    }
+
+    fn backward(&self, cache: &NormCache<F>, error: &Array2<F>) -> Array2<F> {
+        let error_normalized = error * &self.gamma;
+
+        match &cache.stats {
+            // the mean and variance depend on every sample of the batch
+            Some(_) => {
+                let n = F::of(error.nrows() as f64);
+
+                let sum = error_normalized.sum_axis(Axis(0));
+                let dot = (&error_normalized * &cache.normalized).sum_axis(Axis(0));
+
+                (error_normalized * n - &sum - &cache.normalized * &dot) * &cache.inv_std / n
+            },
+            None => error_normalized * &cache.inv_std
+        }
+    }
+
+    fn track(&mut self, caches: &[&NormCache<F>]) {
+        let mut mean = Array1::zeros(self.running_mean.raw_dim());
+        let mut var = Array1::zeros(self.running_var.raw_dim());
+        let mut samples = 0;
+
+        for cache in caches {
+            if let Some((batch_mean, batch_var)) = &cache.stats {
+                let n = cache.normalized.nrows();
+
+                mean.scaled_add(F::of(n as f64), batch_mean);
+                var.scaled_add(F::of(n as f64), batch_var);
+                samples += n;
+            }
+        }
+
+        if samples > 0 {
+            let samples = F::of(samples as f64);
+            let momentum = F::of(self.momentum.into());
+
+            self.running_mean = &self.running_mean * momentum + &mean * ((F::one() - momentum) / samples);
+            self.running_var = &self.running_var * momentum + &var * ((F::one() - momentum) / samples);
+        }
+    }
+}
+
+/// y = gamma * (z - mean) / sqrt(var + epsilon) + beta, with the statistics of each sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerNorm<F: Float = f32> {
+    pub gamma: Array1<F>,
+    pub beta: Array1<F>,
+    pub epsilon: f32
+}
+
+impl<F: Float> LayerNorm<F> {
+    pub fn new(size: usize, epsilon: f32) -> Self {
+        Self {
+            gamma: Array1::ones(size),
+            beta: Array1::zeros(size),
+            epsilon
+        }
+    }
+
+    fn forward(&self, unscaled: &Array2<F>) -> (Array2<F>, NormCache<F>) {
+        let mean = unscaled.mean_axis(Axis(1)).expect("Empty layer").insert_axis(Axis(1));
+        let var = unscaled.var_axis(Axis(1), F::zero());
+
+        let inv_std = var.mapv(|var| F::one() / (var + F::of(self.epsilon.into())).sqrt());
+        let normalized = (unscaled - &mean) * inv_std.view().insert_axis(Axis(1));
+        let value = &normalized * &self.gamma + &self.beta;
+
+        (value, NormCache { normalized, inv_std, stats: None })
+    }
+
+    fn backward(&self, cache: &NormCache<F>, error: &Array2<F>) -> Array2<F> {
+        // the mean and variance depend on every neuron of the sample
+        let n = F::of(error.ncols() as f64);
+        let error_normalized = error * &self.gamma;
+
+        let sum = error_normalized.sum_axis(Axis(1)).insert_axis(Axis(1));
+        let dot = (&error_normalized * &cache.normalized).sum_axis(Axis(1)).insert_axis(Axis(1));
+
+        (error_normalized * n - &sum - &cache.normalized * &dot) * cache.inv_std.view().insert_axis(Axis(1)) / n
+    }
+}
+
+/// Values of a normalization for a whole batch
+#[derive(Debug, Clone, Default)]
+pub struct NormCache<F: Float> {
+    normalized: Array2<F>,
+    /// One per neuron for batch normalization, one per sample for layer normalization
+    inv_std: Array1<F>,
+    /// Mean and variance of the batch, `None` when the running ones were used
+    stats: Option<(Array1<F>, Array1<F>)>
+}
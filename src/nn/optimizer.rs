@@ -0,0 +1,232 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, ArrayView, ArrayViewMut, Dimension, Zip};
+
+use super::Float;
+
+/// Remembers what an optimizer needs between steps for a single array of parameters
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OptimizerState<D: Dimension, F: Float = f32> {
+    /// velocity or first moment
+    first_m: Array<F, D>,
+    /// second moment
+    second_v: Array<F, D>
+}
+
+impl<D: Dimension, F: Float> OptimizerState<D, F> {
+    /// States are created lazily, so that changing the optimizer or loading an old save just starts from scratch
+    fn fit(&mut self, shape: D) {
+        if self.first_m.raw_dim() != shape {
+            self.first_m = Array::zeros(shape.clone());
+            self.second_v = Array::zeros(shape);
+        }
+    }
+}
+
+/// Updates the parameters of the network given their gradients
+pub trait Optimizer {
+    /// `step` is the amount of updates done so far, starting from 1
+    fn update<F: Float, D: Dimension>(&self, value: ArrayViewMut<'_, F, D>, gradient: &Array<F, D>, state: &mut OptimizerState<D, F>, rate: F, step: i32);
+
+    /// Whether `update` would leave every value finite, nothing is written
+    fn finite<F: Float, D: Dimension>(&self, value: ArrayView<'_, F, D>, gradient: &Array<F, D>, state: &OptimizerState<D, F>, rate: F, step: i32) -> bool;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OptimizerFunction {
+    /// Plain gradient descent
+    #[default]
+    #[serde(alias = "SGD")]
+    Sgd,
+    /// Keeps moving in the direction of the previous steps
+    Momentum { beta: f32 },
+    /// Momentum looking ahead at where the velocity is bringing it
+    Nesterov { beta: f32 },
+    /// Divides each step by a running average of the squared gradients
+    RMSProp { decay: f32, epsilon: f32 },
+    /// Momentum and RMSProp combined, with bias correction
+    Adam { beta1: f32, beta2: f32, epsilon: f32 },
+    /// Adam with weight decay applied directly to the values
+    AdamW { beta1: f32, beta2: f32, epsilon: f32, weight_decay: f32 }
+}
+
+impl OptimizerFunction {
+    pub fn momentum() -> Self {
+        OptimizerFunction::Momentum { beta: 0.9 }
+    }
+
+    pub fn nesterov() -> Self {
+        OptimizerFunction::Nesterov { beta: 0.9 }
+    }
+
+    pub fn rmsprop() -> Self {
+        OptimizerFunction::RMSProp { decay: 0.9, epsilon: 1e-8 }
+    }
+
+    pub fn adam() -> Self {
+        OptimizerFunction::Adam { beta1: 0.9, beta2: 0.999, epsilon: 1e-8 }
+    }
+
+    pub fn adamw(weight_decay: f32) -> Self {
+        OptimizerFunction::AdamW { beta1: 0.9, beta2: 0.999, epsilon: 1e-8, weight_decay }
+    }
+}
+
+impl OptimizerFunction {
+    /// Plain gradient descent keeps nothing between steps
+    fn moments(&self) -> bool {
+        *self != OptimizerFunction::Sgd
+    }
+
+    /// The update of a single value given its gradient and its moments, which it updates in place
+    fn rule<F: Float>(&self, rate: F, step: i32) -> impl Fn(F, F, &mut F, &mut F) -> F {
+        let of = |hyperparameter: f32| F::of(hyperparameter.into());
+        let one = F::one();
+        let optimizer = *self;
+
+        // the moments of adam start at 0, correct them for it
+        let (correction1, correction2) = match optimizer {
+            OptimizerFunction::Adam { beta1, beta2, .. } | OptimizerFunction::AdamW { beta1, beta2, .. } => {
+                (one - of(beta1).powi(step), one - of(beta2).powi(step))
+            },
+            _ => (one, one)
+        };
+
+        move |value, gradient, first, second| match optimizer {
+            OptimizerFunction::Sgd => {
+                value - gradient * rate
+            },
+            OptimizerFunction::Momentum { beta } => {
+                *first = of(beta) * *first + gradient;
+                value - *first * rate
+            },
+            OptimizerFunction::Nesterov { beta } => {
+                *first = of(beta) * *first + gradient;
+                value - (gradient + of(beta) * *first) * rate
+            },
+            OptimizerFunction::RMSProp { decay, epsilon } => {
+                *second = of(decay) * *second + (one - of(decay)) * gradient * gradient;
+                value - gradient / (second.sqrt() + of(epsilon)) * rate
+            },
+            OptimizerFunction::Adam { beta1, beta2, epsilon } => {
+                adam(value, gradient, first, second, (of(beta1), of(beta2), of(epsilon), F::zero()), (correction1, correction2), rate)
+            },
+            OptimizerFunction::AdamW { beta1, beta2, epsilon, weight_decay } => {
+                adam(value, gradient, first, second, (of(beta1), of(beta2), of(epsilon), of(weight_decay)), (correction1, correction2), rate)
+            }
+        }
+    }
+}
+
+impl Optimizer for OptimizerFunction {
+    fn update<F: Float, D: Dimension>(&self, value: ArrayViewMut<'_, F, D>, gradient: &Array<F, D>, state: &mut OptimizerState<D, F>, rate: F, step: i32) {
+        assert_eq!(value.shape(), gradient.shape(), "Gradient not of same size!");
+
+        let rule = self.rule(rate, step);
+
+        if self.moments() {
+            state.fit(value.raw_dim());
+
+            Zip::from(value).and(&mut state.first_m).and(&mut state.second_v).and(gradient).for_each(|value, first, second, &gradient| {
+                *value = rule(*value, gradient, first, second)
+            })
+        } else {
+            Zip::from(value).and(gradient).for_each(|value, &gradient| {
+                *value = rule(*value, gradient, &mut F::zero(), &mut F::zero())
+            })
+        }
+    }
+
+    fn finite<F: Float, D: Dimension>(&self, value: ArrayView<'_, F, D>, gradient: &Array<F, D>, state: &OptimizerState<D, F>, rate: F, step: i32) -> bool {
+        assert_eq!(value.shape(), gradient.shape(), "Gradient not of same size!");
+
+        let rule = self.rule(rate, step);
+
+        // the moments are copied, a state that was never fit starts at 0
+        if self.moments() && state.first_m.raw_dim() == value.raw_dim() {
+            Zip::from(value).and(&state.first_m).and(&state.second_v).and(gradient).all(|&value, &first, &second, &gradient| {
+                let (mut first, mut second) = (first, second);
+
+                rule(value, gradient, &mut first, &mut second).is_finite()
+            })
+        } else {
+            Zip::from(value).and(gradient).all(|&value, &gradient| {
+                rule(value, gradient, &mut F::zero(), &mut F::zero()).is_finite()
+            })
+        }
+    }
+}
+
+/// `hyperparameters` are beta1, beta2, epsilon and the weight decay, `corrections` those of both moments
+fn adam<F: Float>(value: F, gradient: F, first: &mut F, second: &mut F, hyperparameters: (F, F, F, F), corrections: (F, F), rate: F) -> F {
+    let (beta1, beta2, epsilon, weight_decay) = hyperparameters;
+    let one = F::one();
+
+    *first = beta1 * *first + (one - beta1) * gradient;
+    *second = beta2 * *second + (one - beta2) * gradient * gradient;
+
+    let first = *first / corrections.0;
+    let second = *second / corrections.1;
+
+    value - (first / (second.sqrt() + epsilon) + weight_decay * value) * rate
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array1, Ix1};
+
+    use super::*;
+
+    #[test]
+    fn first_step_of_adam_moves_every_value_by_the_rate() {
+        let mut value = array![1.0, -2.0, 0.5];
+        let mut state = OptimizerState::<Ix1, f64>::default();
+
+        OptimizerFunction::adam().update(value.view_mut(), &array![0.3, -4.0, 1e-3], &mut state, 0.1, 1);
+
+        for (value, expected) in value.iter().zip([0.9, -1.9, 0.4]) {
+            assert!((value - expected).abs() < 1e-6, "{value} != {expected}");
+        }
+    }
+
+    #[test]
+    fn first_step_of_every_optimizer() {
+        let expectations = [
+            (OptimizerFunction::Sgd, [0.95, -1.9]),
+            (OptimizerFunction::momentum(), [0.95, -1.9]),
+            // the gradient plus the velocity it just added to
+            (OptimizerFunction::nesterov(), [0.905, -1.81]),
+            // the squared gradient weighted by 1 - decay, so every value moves by rate / sqrt(0.1)
+            (OptimizerFunction::rmsprop(), [1.0 - 0.1 / 0.1f64.sqrt(), -2.0 + 0.1 / 0.1f64.sqrt()]),
+            // adam moves by the rate, the decay adds rate * 0.01 * value
+            (OptimizerFunction::adamw(0.01), [0.899, -1.898])
+        ];
+
+        for (optimizer, expected) in expectations {
+            let mut value = array![1.0, -2.0];
+            let mut state = OptimizerState::<Ix1, f64>::default();
+
+            optimizer.update(value.view_mut(), &array![0.5, -1.0], &mut state, 0.1, 1);
+
+            for (value, expected) in value.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-6, "{optimizer:?}: {value} != {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn checking_writes_nothing() {
+        let value: Array1<f64> = array![1.0, -2.0];
+        let gradient = array![0.5, 0.5];
+
+        for optimizer in [OptimizerFunction::Sgd, OptimizerFunction::momentum(), OptimizerFunction::nesterov(), OptimizerFunction::rmsprop(), OptimizerFunction::adam(), OptimizerFunction::adamw(0.01)] {
+            let mut state = OptimizerState::default();
+
+            optimizer.update(value.clone().view_mut(), &gradient, &mut state, 0.1, 1);
+            let before = state.clone();
+
+            assert!(optimizer.finite(value.view(), &gradient, &state, 0.1, 2));
+            assert!(!optimizer.finite(value.view(), &gradient, &state, f64::INFINITY, 2));
+            assert_eq!((before.first_m, before.second_v), (state.first_m.clone(), state.second_v.clone()));
+        }
+    }
+}
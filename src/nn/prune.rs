@@ -0,0 +1,447 @@
+use std::fmt;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, Array1, Array2, ArrayBase, ArrayD, Axis, DataMut, Dimension, Zip};
+
+use super::{NN, Graph, Input, Output, Sample, Norm, ActivationFunction, NNError, Float, batch, SCORE_BATCH};
+use super::sequential::{Layer, ParameterKind};
+use super::quantize::correct;
+
+/// Fraction of the weights to prune, the ones with the smallest magnitude go first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Sparsity {
+    /// Of all the weights of the model together, layers with many small weights lose more
+    Global(f32),
+    /// Of every weight on its own, one target per weight in the order of the layers
+    Layer(Vec<f32>)
+}
+
+impl Sparsity {
+    /// Target after `round` of `rounds`, growing fast at first and slowly at the end
+    /// when every weight left matters more (the cubic schedule of Zhu and Gupta)
+    pub fn gradual(&self, round: usize, rounds: usize) -> Self {
+        let progress = 1.0 - (1.0 - round.min(rounds) as f32 / rounds.max(1) as f32).powi(3);
+
+        match self {
+            Self::Global(target) => Self::Global(target * progress),
+            Self::Layer(targets) => Self::Layer(targets.iter().map(|target| target * progress).collect())
+        }
+    }
+}
+
+/// Sets the values that aren't kept to 0
+pub(super) fn zero_pruned<F: Float, S: DataMut<Elem = F>, D: Dimension>(values: &mut ArrayBase<S, D>, mask: &Array<bool, D>) {
+    Zip::from(values).and(mask).for_each(|value, &kept| {
+        if !kept {
+            *value = F::zero();
+        }
+    });
+}
+
+/// Marks the `count` smallest weights as pruned, weights pruned before always come first
+fn prune_smallest<F: Float>(mut weights: Vec<(F, usize, usize)>, count: usize, masks: &mut [ArrayD<bool>]) {
+    let count = count.min(weights.len());
+
+    if count == 0 {
+        return
+    }
+
+    weights.select_nth_unstable_by(count - 1, |a, b| a.0.partial_cmp(&b.0).expect("Weights not finite"));
+
+    for &(_, weight_i, i) in &weights[..count] {
+        masks[weight_i].as_slice_mut().expect("Mask in standard layout")[i] = false;
+    }
+}
+
+impl<F: Float> Graph<F> {
+    /// Every weight of the model as (layer, parameter), what pruning works on
+    fn weights(&self) -> Vec<(usize, usize)> {
+        self.layers.iter().enumerate().flat_map(|(layer_i, layer)| {
+            layer.kinds().into_iter().enumerate()
+                .filter(|(_, kind)| *kind == ParameterKind::Weight)
+                .map(move |(parameter_i, _)| (layer_i, parameter_i))
+        }).collect()
+    }
+
+    /// Pruned values of a weight
+    fn pruned_of(&self, (layer_i, parameter_i): (usize, usize)) -> usize {
+        self.masks.get(layer_i).and_then(|masks| masks[parameter_i].as_ref()).map_or(0, |mask| mask.iter().filter(|kept| !**kept).count())
+    }
+
+    /// Prunes the weights with the smallest magnitude until `sparsity` is reached, they are set to 0
+    /// and stay 0 while training. Sparsity only grows, weights pruned before stay pruned.
+    pub fn prune(&mut self, sparsity: &Sparsity) {
+        self.fit();
+
+        let weights = self.weights();
+        let mut masks: Vec<ArrayD<bool>> = weights.iter().map(|&(layer_i, parameter_i)| {
+            ArrayD::from_elem(self.layers[layer_i].parameters()[parameter_i].raw_dim(), true)
+        }).collect();
+
+        // the magnitude of every weight, the pruned ones below any other
+        let magnitudes = |weight_i: usize| -> Vec<(F, usize, usize)> {
+            let (layer_i, parameter_i) = weights[weight_i];
+            let parameters = self.layers[layer_i].parameters();
+            let value = &parameters[parameter_i];
+
+            let kept: Vec<bool> = match &self.masks[layer_i][parameter_i] {
+                Some(mask) => mask.iter().copied().collect(),
+                None => vec![true; value.len()]
+            };
+
+            value.iter().zip(kept).enumerate().map(|(i, (value, kept))| {
+                (if kept { value.abs() } else { -F::one() }, weight_i, i)
+            }).collect()
+        };
+
+        match sparsity {
+            Sparsity::Global(target) => {
+                assert!((0.0..=1.0).contains(target), "Invalid sparsity {target}, should be in 0..=1");
+
+                let values: Vec<(F, usize, usize)> = (0..weights.len()).flat_map(magnitudes).collect();
+                let count = (values.len() as f32 * target).round() as usize;
+
+                prune_smallest(values, count.max(self.pruned()), &mut masks);
+            },
+            Sparsity::Layer(targets) => {
+                assert_eq!(targets.len(), weights.len(), "Invalid sparsity, one target per weight");
+
+                for (weight_i, target) in targets.iter().enumerate() {
+                    assert!((0.0..=1.0).contains(target), "Invalid sparsity {target}, should be in 0..=1");
+
+                    let values = magnitudes(weight_i);
+                    let count = (values.len() as f32 * target).round() as usize;
+
+                    prune_smallest(values, count.max(self.pruned_of(weights[weight_i])), &mut masks);
+                }
+            }
+        }
+
+        for (&(layer_i, parameter_i), mask) in weights.iter().zip(masks) {
+            let mut parameters = self.layers[layer_i].parameters_mut();
+            zero_pruned(&mut parameters[parameter_i], &mask);
+
+            self.masks[layer_i][parameter_i] = Some(mask);
+        }
+    }
+
+    /// Prunes to `target` in `rounds` steps of the gradual schedule, between them `fine_tune`
+    /// trains the model so that it recovers from the weights it lost
+    pub fn prune_gradually(&mut self, target: &Sparsity, rounds: usize, mut fine_tune: impl FnMut(&mut Self) -> Result<(), NNError>) -> Result<(), NNError> {
+        for round in 1..=rounds {
+            self.prune(&target.gradual(round, rounds));
+
+            fine_tune(self)?;
+        }
+
+        Ok(())
+    }
+
+    fn pruned(&self) -> usize {
+        self.weights().into_iter().map(|weight| self.pruned_of(weight)).sum()
+    }
+
+    /// Fraction of the weights that are pruned
+    pub fn sparsity(&self) -> f32 {
+        let weights: usize = self.weights().into_iter().map(|(layer_i, parameter_i)| self.layers[layer_i].parameters()[parameter_i].len()).sum();
+
+        self.pruned() as f32 / weights.max(1) as f32
+    }
+}
+
+impl<F: Float> NN<F> {
+    /// Same as `SparseNN::new`
+    pub fn sparse(&self) -> SparseNN<F> {
+        SparseNN::new(self)
+    }
+
+    /// Accuracy and speed of the sparse inference at every global sparsity, each from a copy of the network
+    /// pruned in one shot without fine-tuning. The network itself is left as it is.
+    pub fn pruning_series(&self, sparsities: &[f32], samples: &[Sample<F>]) -> Vec<PruningReport> {
+        sparsities.iter().map(|&sparsity| {
+            let mut pruned = self.clone();
+            pruned.prune(&Sparsity::Global(sparsity));
+
+            pruned.sparse().report(&pruned, samples)
+        }).collect()
+    }
+}
+
+/// Weight matrix in compressed sparse rows, only the weights that aren't 0 are stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Csr<F: Float = f32> {
+    pub values: Vec<F>,
+    /// Column of every value
+    pub columns: Vec<u32>,
+    /// The values of row i are values[rows[i]..rows[i + 1]]
+    pub rows: Vec<usize>,
+    pub ncols: usize
+}
+
+impl<F: Float> Csr<F> {
+    pub fn new(dense: &Array2<F>) -> Self {
+        let mut csr = Self { values: Vec::new(), columns: Vec::new(), rows: vec![0], ncols: dense.ncols() };
+
+        for row in dense.rows() {
+            for (column, &value) in row.iter().enumerate().filter(|(_, value)| !value.is_zero()) {
+                csr.values.push(value);
+                csr.columns.push(column as u32);
+            }
+
+            csr.rows.push(csr.values.len());
+        }
+
+        csr
+    }
+
+    pub fn nrows(&self) -> usize {
+        self.rows.len() - 1
+    }
+
+    /// inputs . transposed, (batch x ncols) . (ncols x nrows) like the dense weights are used
+    fn product(&self, inputs: &Array2<F>) -> Array2<F> {
+        let mut outputs = Array2::zeros((inputs.nrows(), self.nrows()));
+
+        for (input, mut output) in inputs.rows().into_iter().zip(outputs.rows_mut()) {
+            for (i, output) in output.iter_mut().enumerate() {
+                let range = self.rows[i]..self.rows[i + 1];
+
+                *output = self.values[range.clone()].iter().zip(&self.columns[range]).map(|(&value, &column)| value * input[column as usize]).sum();
+            }
+        }
+
+        outputs
+    }
+
+    fn bytes(&self) -> usize {
+        self.values.len() * size_of::<F>() + self.columns.len() * size_of::<u32>() + self.rows.len() * size_of::<usize>()
+    }
+}
+
+/// A layer of a `SparseNN`, the connections coming from the previous layer are sparse
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparseLayer<F: Float = f32> {
+    pub weights: Csr<F>,
+    pub bias_b: Array1<F>,
+    pub activation: ActivationFunction,
+    pub norm: Option<Norm<F>>
+}
+
+/// Pruned `NN` for inference, every product only goes over the weights that are left.
+/// Fake quantization isn't applied, it computes with the weights as trained.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparseNN<F: Float = f32> {
+    pub layers: Vec<SparseLayer<F>>
+}
+
+impl<F: Float> SparseNN<F> {
+    pub fn new(nn: &NN<F>) -> Self {
+        let layers = nn.blocks().into_iter().map(|block| SparseLayer {
+            weights: Csr::new(&block.dense.value_w),
+            bias_b: block.dense.bias_b.clone(),
+            activation: block.activation,
+            norm: block.norm.cloned()
+        }).collect();
+
+        Self { layers }
+    }
+
+    pub fn predict(&self, input: &Input<F>) -> Output<F> {
+        let inputs = input.view().insert_axis(Axis(0)).to_owned();
+
+        self.predict_batch(&inputs).row(0).to_vec()
+    }
+
+    /// Output of every input, one sample per row
+    pub fn predict_batch(&self, inputs: &Array2<F>) -> Array2<F> {
+        assert_eq!(inputs.ncols(), self.layers[0].weights.ncols, "Input layers not of same size!");
+
+        self.layers.iter().fold(inputs.clone(), |value_a, layer| {
+            let mut unscaled_z = layer.weights.product(&value_a) + &layer.bias_b;
+
+            if let Some(norm) = &layer.norm {
+                unscaled_z = norm.evaluate(&unscaled_z);
+            }
+
+            layer.activation.activate(&unscaled_z)
+        })
+    }
+
+    /// Size of the weights and the biases, normalizations aren't counted
+    pub fn bytes(&self) -> usize {
+        self.layers.iter().map(|layer| layer.weights.bytes() + layer.bias_b.len() * size_of::<F>()).sum()
+    }
+
+    /// Accuracy and inference time over `samples`, next to the dense network it came from
+    pub fn report(&self, nn: &NN<F>, samples: &[Sample<F>]) -> PruningReport {
+        let mut right = 0;
+        let (mut dense_time, mut sparse_time) = (Duration::ZERO, Duration::ZERO);
+
+        for chunk in samples.chunks(SCORE_BATCH) {
+            let (inputs, outputs) = batch(chunk);
+
+            let start = Instant::now();
+            nn.predict_batch(&inputs);
+            dense_time += start.elapsed();
+
+            let start = Instant::now();
+            let predicted = self.predict_batch(&inputs);
+            sparse_time += start.elapsed();
+
+            right += correct(&predicted, &outputs);
+        }
+
+        let weights: usize = nn.blocks().iter().map(|block| block.dense.value_w.len()).sum();
+        let biases: usize = nn.blocks().iter().map(|block| block.dense.bias_b.len()).sum();
+
+        PruningReport {
+            sparsity: nn.sparsity() * 100.0,
+            samples: samples.len(),
+            accuracy: right as f32 / samples.len().max(1) as f32 * 100.0,
+            dense_time,
+            sparse_time,
+            dense_bytes: (weights + biases) * size_of::<F>(),
+            sparse_bytes: self.bytes()
+        }
+    }
+}
+
+/// Accuracy of a pruned network and the time its inference takes, dense and sparse
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PruningReport {
+    /// Percentage of the weights that are pruned
+    pub sparsity: f32,
+    pub samples: usize,
+    /// Percentage of the samples that are right
+    pub accuracy: f32,
+    pub dense_time: Duration,
+    pub sparse_time: Duration,
+    pub dense_bytes: usize,
+    pub sparse_bytes: usize
+}
+
+impl PruningReport {
+    /// How many times faster the sparse inference is
+    pub fn speedup(&self) -> f32 {
+        self.dense_time.as_secs_f32() / self.sparse_time.as_secs_f32().max(f32::MIN_POSITIVE)
+    }
+}
+
+impl fmt::Display for PruningReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{}% pruned on {} samples: {}% right, sparse inference took {:?} instead of {:?} ({}x), {} bytes instead of {}",
+            self.sparsity, self.samples, self.accuracy, self.sparse_time, self.dense_time, self.speedup(), self.sparse_bytes, self.dense_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{LayerConfig, Normalization, OptimizerFunction, Workspace, samples};
+
+    fn nn() -> Box<NN> {
+        let configs = [
+            LayerConfig::new(8, ActivationFunction::Tanh).norm(Normalization::layer()),
+            LayerConfig::new(6, ActivationFunction::ReLU),
+            LayerConfig::new(2, ActivationFunction::Sigmoid)
+        ];
+
+        NN::from_seed(3, &configs, 7)
+    }
+
+    /// Fraction of every weight that is 0
+    fn zeros(nn: &NN) -> Vec<f32> {
+        nn.blocks().iter().map(|block| block.dense.value_w.iter().filter(|value| **value == 0.0).count() as f32 / block.dense.value_w.len() as f32).collect()
+    }
+
+    #[test]
+    fn global_and_per_layer_targets_are_reached() {
+        let mut nn = nn();
+        nn.prune(&Sparsity::Global(0.6));
+
+        // 3 * 8 + 8 * 6 + 6 * 2 weights
+        assert_eq!(nn.sparsity(), (84.0f32 * 0.6).round() / 84.0);
+        assert!((nn.sparsity() - 0.6).abs() < 0.01);
+
+        let mut nn = self::nn();
+        nn.prune(&Sparsity::Layer(vec![0.25, 0.5, 0.75]));
+
+        for (zeros, target) in zeros(&nn).into_iter().zip([0.25, 0.5, 0.75]) {
+            assert!((zeros - target).abs() < 0.05, "{zeros} instead of {target}");
+        }
+
+        // sparsity only grows
+        nn.prune(&Sparsity::Layer(vec![0.0, 0.0, 0.0]));
+        assert!(zeros(&nn).iter().zip([0.25, 0.5, 0.75]).all(|(zeros, target)| (zeros - target).abs() < 0.05));
+    }
+
+    #[test]
+    fn pruned_weights_stay_zero_through_momentum() {
+        let mut nn = nn().with_optimizer(OptimizerFunction::adam());
+
+        let (inputs, outputs) = batch(&samples());
+        let mut workspace = Workspace::new(&nn);
+
+        // the moments of every weight are built up before pruning
+        for _ in 0..10 {
+            nn.train_batch(&mut workspace, &inputs, &outputs, 0.01).unwrap();
+        }
+
+        nn.prune(&Sparsity::Global(0.5));
+        let pruned: Vec<Array2<bool>> = nn.blocks().iter().map(|block| block.dense.value_w.mapv(|value| value == 0.0)).collect();
+
+        for _ in 0..10 {
+            nn.train_batch(&mut workspace, &inputs, &outputs, 0.01).unwrap();
+        }
+
+        for (block, pruned) in nn.blocks().iter().zip(&pruned) {
+            assert!(block.dense.value_w.iter().zip(pruned).all(|(&value, &pruned)| !pruned || value == 0.0), "{}", block.dense.value_w);
+        }
+
+        assert_eq!(nn.sparsity(), (84.0f32 * 0.5).round() / 84.0);
+    }
+
+    #[test]
+    fn gradual_schedule_only_grows() {
+        let targets: Vec<f32> = (0..=10).map(|round| match Sparsity::Global(0.9).gradual(round, 10) {
+            Sparsity::Global(target) => target,
+            Sparsity::Layer(_) => unreachable!()
+        }).collect();
+
+        assert_eq!(targets[0], 0.0);
+        assert!((targets[10] - 0.9).abs() < 1e-6);
+        assert!(targets.windows(2).all(|pair| pair[0] < pair[1]), "{targets:?}");
+
+        // the steps get smaller
+        assert!(targets.windows(3).all(|steps| steps[1] - steps[0] > steps[2] - steps[1]), "{targets:?}");
+    }
+
+    #[test]
+    fn sparse_inference_matches_the_dense_one() {
+        let mut nn = nn();
+        nn.prune(&Sparsity::Global(0.7));
+
+        let sparse = nn.sparse();
+        let (inputs, _) = batch(&samples());
+        let difference = (&nn.predict_batch(&inputs) - &sparse.predict_batch(&inputs)).mapv(f32::abs);
+
+        assert!(difference.iter().all(|&difference| difference < 1e-6), "{difference}");
+        assert_eq!(sparse.layers.iter().map(|layer| layer.weights.values.len()).sum::<usize>(), 84 - (84.0f32 * 0.7).round() as usize);
+    }
+
+    #[test]
+    fn series_goes_over_every_sparsity() {
+        let nn = nn();
+        let series = nn.pruning_series(&[0.0, 0.5, 0.9], &samples());
+
+        let sparsities: Vec<f32> = series.iter().map(|report| report.sparsity.round()).collect();
+        assert_eq!(sparsities, [0.0, 50.0, 90.0]);
+        assert!(series.windows(2).all(|pair| pair[0].sparse_bytes > pair[1].sparse_bytes));
+
+        assert_eq!(nn.sparsity(), 0.0);
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt;
+use std::mem::size_of;
+
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, Array1, Array2, ArrayView1, Axis, Dimension, Zip};
+use num_traits::clamp;
+
+use super::{NN, Input, Output, Sample, Norm, ActivationFunction, Float, batch, SCORE_BATCH};
+use super::sequential::{Layer, LayerKind};
+
+/// How many scales the int8 weights of a layer share
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Granularity {
+    /// One scale for the whole weight matrix
+    Layer,
+    /// One scale per output neuron, a neuron with small weights keeps its precision
+    #[default]
+    Channel
+}
+
+/// Quantization-aware training, the weights and the activated values are rounded in the forward pass
+/// like they will be once quantized, while backpropagation ignores the rounding (straight-through estimator).
+/// The model learns weights that survive the rounding, a `NN` exports them with `NN::export_quantized`.
+/// Dense and convolution layers round their weights, `Quantize` layers the values going through them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FakeQuantization {
+    /// 8 for int8, 4 for int4
+    pub bits: u32,
+    pub granularity: Granularity,
+    /// How much of the running ranges of the activated values is kept every step
+    pub momentum: f32
+}
+
+impl FakeQuantization {
+    pub fn new(bits: u32, granularity: Granularity) -> Self {
+        assert!((2..=8).contains(&bits), "Invalid quantization to {bits} bits, should be in 2..=8");
+
+        Self { bits, granularity, momentum: 0.99 }
+    }
+
+    pub fn int8() -> Self {
+        Self::new(8, Granularity::Channel)
+    }
+
+    pub fn int4() -> Self {
+        Self::new(4, Granularity::Channel)
+    }
+
+    /// Weights rounded to the values they will have once quantized
+    pub(super) fn weights<F: Float>(&self, value_w: &Array2<F>) -> Array2<F> {
+        let scale_w = weight_scales(value_w, self.granularity, self.bits);
+        let max = weight_max(self.bits);
+
+        Array2::from_shape_fn(value_w.dim(), |(i, j)| F::of(round(value_w[(i, j)] / scale_w[i], -max, max).into()) * scale_w[i])
+    }
+
+    /// Activated values rounded to the values they will have once quantized in `range`
+    pub(super) fn values<F: Float, D: Dimension>(&self, value_a: &Array<F, D>, range: (F, F)) -> Array<F, D> {
+        let (scale_a, zero_a) = value_scale(range, self.bits);
+        let zero = F::of(zero_a.into());
+
+        value_a.mapv(|value| (F::of(round(value / scale_a + zero, 0, value_max(self.bits)).into()) - zero) * scale_a)
+    }
+
+    /// Running range after a batch, the first batch is taken as is
+    pub(super) fn track<F: Float>(&self, running: Option<(F, F)>, (min, max): (F, F)) -> (F, F) {
+        let momentum = F::of(self.momentum.into());
+
+        match running {
+            Some((running_min, running_max)) => (
+                running_min * momentum + min * (F::one() - momentum),
+                running_max * momentum + max * (F::one() - momentum)
+            ),
+            None => (min, max)
+        }
+    }
+}
+
+/// Smallest and biggest value, zero is always included so that it is represented exactly
+pub(super) fn range<F: Float, D: Dimension>(values: &Array<F, D>) -> (F, F) {
+    values.fold((F::zero(), F::zero()), |(min, max), &value| (min.min(value), max.max(value)))
+}
+
+/// The rounding passes the error straight through, except for the values that were clamped to the range
+pub(super) fn straight_through<F: Float, D: Dimension>(error: &mut Array<F, D>, value_a: &Array<F, D>, (min, max): (F, F)) {
+    Zip::from(error).and(value_a).for_each(|error, &value| {
+        if value < min || value > max {
+            *error = F::zero();
+        }
+    });
+}
+
+/// A layer of a `QuantizedNN`, weights are symmetric signed integers and the values coming
+/// from the previous layer are asymmetric unsigned ones, value = scale * (quantized - zero)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantizedLayer<F: Float = f32> {
+    /// Shape (curr, prev) like `Dense::value_w`
+    pub weights: Array2<i8>,
+    /// Scale of the weights of every neuron, all the same with `Granularity::Layer`
+    pub scale_w: Array1<F>,
+    /// In units of scale_w * scale_a, so it can be added to the accumulator
+    pub bias: Array1<i32>,
+    /// Quantization of the activated values of the previous layer
+    pub scale_a: F,
+    pub zero_a: u8,
+    pub activation: ActivationFunction,
+    /// Normalizations and activations are applied in float on the dequantized values
+    pub norm: Option<Norm<F>>
+}
+
+fn eight() -> u32 {
+    8
+}
+
+/// Post-training int8 quantization of a `NN` for inference.
+/// The matrix products are done in integers, accumulated in i32,
+/// only the normalizations and the activations are computed in float.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantizedNN<F: Float = f32> {
+    pub granularity: Granularity,
+    /// Below 8 the values still take a byte each, they only use less of its range
+    #[serde(default = "eight")]
+    pub bits: u32,
+    pub layers: Vec<QuantizedLayer<F>>
+}
+
+/// Smallest and biggest value fed to the dense layer of every block over the calibration samples
+fn ranges<F: Float>(nn: &NN<F>, calibration: &[Sample<F>]) -> Vec<(F, F)> {
+    let mut ranges = vec![(F::zero(), F::zero()); nn.blocks().len()];
+
+    for chunk in calibration.chunks(SCORE_BATCH) {
+        let (inputs, _) = batch(chunk);
+        let mut values = inputs.into_dyn();
+        let mut block = 0;
+
+        // the model of a `NN` is a chain, every layer feeds the next
+        for layer in nn.layers.iter() {
+            if let LayerKind::Dense(_) = layer {
+                let (min, max) = range(&values);
+                let widened = &mut ranges[block];
+
+                *widened = (widened.0.min(min), widened.1.max(max));
+                block += 1;
+            }
+
+            values = layer.evaluate(values);
+        }
+    }
+
+    ranges
+}
+
+// biases are kept well inside i32, so that adding them to an accumulator can't overflow
+const BIAS_LIMIT: i32 = i32::MAX / 2;
+
+/// Rounds to the nearest integer in min..=max
+fn round<F: Float>(value: F, min: i32, max: i32) -> i32 {
+    clamp(value.round(), F::of(min.into()), F::of(max.into())).to_i32().expect("Quantized value not finite")
+}
+
+/// Biggest quantized weight, 127 for int8
+fn weight_max(bits: u32) -> i32 {
+    (1 << (bits - 1)) - 1
+}
+
+/// Biggest quantized activated value, 255 for int8
+fn value_max(bits: u32) -> i32 {
+    (1 << bits) - 1
+}
+
+/// Symmetric scale mapping the biggest absolute value to the biggest quantized weight
+fn symmetric<'a, F: Float>(values: impl Iterator<Item = &'a F>, bits: u32) -> F {
+    let max = values.fold(F::zero(), |max, value| max.max(value.abs()));
+
+    if max > F::zero() { max / F::of(weight_max(bits).into()) } else { F::one() }
+}
+
+/// Scale of the weights of every neuron
+fn weight_scales<F: Float>(value_w: &Array2<F>, granularity: Granularity, bits: u32) -> Array1<F> {
+    match granularity {
+        Granularity::Layer => Array1::from_elem(value_w.nrows(), symmetric(value_w.iter(), bits)),
+        Granularity::Channel => value_w.rows().into_iter().map(|row| symmetric(row.iter(), bits)).collect()
+    }
+}
+
+/// Asymmetric scale and zero point mapping the range onto all the quantized values
+fn value_scale<F: Float>((min, max): (F, F), bits: u32) -> (F, u8) {
+    let scale_a = if max > min { (max - min) / F::of(value_max(bits).into()) } else { F::one() };
+
+    (scale_a, round(-min / scale_a, 0, value_max(bits)) as u8)
+}
+
+impl<F: Float> QuantizedLayer<F> {
+    fn new(value_w: &Array2<F>, bias_b: &Array1<F>, range: (F, F), granularity: Granularity, bits: u32, activation: ActivationFunction, norm: Option<Norm<F>>) -> Self {
+        let scale_w = weight_scales(value_w, granularity, bits);
+        let (scale_a, zero_a) = value_scale(range, bits);
+
+        let max = weight_max(bits);
+        let weights = Array2::from_shape_fn(value_w.dim(), |(i, j)| round(value_w[(i, j)] / scale_w[i], -max, max) as i8);
+        let bias = Array1::from_shape_fn(bias_b.len(), |i| round(bias_b[i] / (scale_w[i] * scale_a), -BIAS_LIMIT, BIAS_LIMIT));
+
+        Self { weights, scale_w, bias, scale_a, zero_a, activation, norm }
+    }
+
+    fn quantize(&self, value_a: &Array2<F>, bits: u32) -> Array2<u8> {
+        let zero = F::of(self.zero_a.into());
+
+        value_a.mapv(|value| round(value / self.scale_a + zero, 0, value_max(bits)) as u8)
+    }
+
+    /// Activated values of the layer given the quantized ones of the previous layer
+    fn forward(&self, quantized_a: &Array2<u8>) -> Array2<F> {
+        let zero = i32::from(self.zero_a);
+
+        // w . (a - zero) = w . a - zero * sum(w), the sums only depend on the weights
+        let sums: Vec<i32> = self.weights.rows().into_iter().map(|row| row.iter().map(|&w| i32::from(w)).sum()).collect();
+
+        let mut unscaled_z = Array2::zeros((quantized_a.nrows(), self.weights.nrows()));
+
+        for (sample, mut unscaled) in quantized_a.rows().into_iter().zip(unscaled_z.rows_mut()) {
+            for (i, row) in self.weights.rows().into_iter().enumerate() {
+                let accumulator: i32 = row.iter().zip(sample.iter()).map(|(&w, &a)| i32::from(w) * i32::from(a)).sum();
+                let accumulator = accumulator - zero * sums[i] + self.bias[i];
+
+                unscaled[i] = F::of(accumulator.into()) * self.scale_w[i] * self.scale_a;
+            }
+        }
+
+        if let Some(norm) = &self.norm {
+            unscaled_z = norm.evaluate(&unscaled_z);
+        }
+
+        self.activation.activate(&unscaled_z)
+    }
+
+    fn bytes(&self) -> usize {
+        self.weights.len() * size_of::<i8>() + self.bias.len() * size_of::<i32>() + (self.scale_w.len() + 1) * size_of::<F>() + size_of::<u8>()
+    }
+}
+
+impl<F: Float> QuantizedNN<F> {
+    /// Quantizes the weights of `nn`, the ranges of its values are calibrated on `calibration`,
+    /// a few hundred samples representative of the data it will see are usually enough.
+    /// Values outside of the calibrated ranges are clamped.
+    pub fn new(nn: &NN<F>, calibration: &[Sample<F>], granularity: Granularity) -> Self {
+        assert!(!calibration.is_empty(), "Calibration needs samples");
+
+        Self::with_ranges(nn, &ranges(nn, calibration), granularity, 8)
+    }
+
+    /// `ranges` of the values fed to the dense layer of every block
+    fn with_ranges(nn: &NN<F>, ranges: &[(F, F)], granularity: Granularity, bits: u32) -> Self {
+        let layers = nn.blocks().iter().zip(ranges).map(|(block, &range)| {
+            QuantizedLayer::new(&block.dense.value_w, &block.dense.bias_b, range, granularity, bits, block.activation, block.norm.cloned())
+        }).collect();
+
+        Self { granularity, bits, layers }
+    }
+
+    pub fn predict(&self, input: &Input<F>) -> Output<F> {
+        let inputs = input.view().insert_axis(Axis(0)).to_owned();
+
+        self.predict_batch(&inputs).row(0).to_vec()
+    }
+
+    /// Output of every input, one sample per row, in float like the network it came from
+    pub fn predict_batch(&self, inputs: &Array2<F>) -> Array2<F> {
+        assert_eq!(inputs.ncols(), self.layers[0].weights.ncols(), "Input layers not of same size!");
+
+        self.layers.iter().fold(inputs.clone(), |value_a, layer| layer.forward(&layer.quantize(&value_a, self.bits)))
+    }
+
+    /// Size of the weights, biases and scales, normalizations stay in float and aren't counted
+    pub fn bytes(&self) -> usize {
+        self.layers.iter().map(|layer| layer.bytes()).sum()
+    }
+
+    /// Compares the classification accuracy with the float network it was quantized from
+    pub fn report(&self, nn: &NN<F>, samples: &[Sample<F>]) -> QuantizationReport {
+        let mut right = (0, 0);
+
+        for chunk in samples.chunks(SCORE_BATCH) {
+            let (inputs, outputs) = batch(chunk);
+
+            right.0 += correct(&nn.predict_batch(&inputs), &outputs);
+            right.1 += correct(&self.predict_batch(&inputs), &outputs);
+        }
+
+        let weights: usize = nn.blocks().iter().map(|block| block.dense.value_w.len()).sum();
+        let biases: usize = nn.blocks().iter().map(|block| block.dense.bias_b.len()).sum();
+
+        QuantizationReport {
+            granularity: self.granularity,
+            bits: self.bits,
+            samples: samples.len(),
+            float_accuracy: right.0 as f32 / samples.len().max(1) as f32 * 100.0,
+            quantized_accuracy: right.1 as f32 / samples.len().max(1) as f32 * 100.0,
+            float_bytes: (weights + biases) * size_of::<F>(),
+            quantized_bytes: self.bytes()
+        }
+    }
+}
+
+impl<F: Float> NN<F> {
+    /// Same as `QuantizedNN::new`
+    pub fn quantize_int8(&self, calibration: &[Sample<F>], granularity: Granularity) -> QuantizedNN<F> {
+        QuantizedNN::new(self, calibration, granularity)
+    }
+
+    /// Quantized weights of a network trained with `FakeQuantization`, in the ranges it tracked while training,
+    /// the integer forward pass then computes what the training forward pass simulated.
+    /// `None` without fake quantization or before the first training step, calibrate with `quantize_int8` instead.
+    pub fn export_quantized(&self) -> Option<QuantizedNN<F>> {
+        let quantization = self.quantization()?;
+        let ranges: Option<Vec<(F, F)>> = self.blocks().iter().map(|block| block.quantize.range).collect();
+
+        Some(QuantizedNN::with_ranges(self, &ranges?, quantization.granularity, quantization.bits))
+    }
+}
+
+/// Samples whose biggest output is at the same place as the biggest expected one
+pub(super) fn correct<F: Float>(predicted: &Array2<F>, expected: &Array2<F>) -> usize {
+    let highest = |row: ArrayView1<F>| row.iter().enumerate().fold(0, |highest, (i, value)| if *value > row[highest] { i } else { highest });
+
+    predicted.rows().into_iter().zip(expected.rows()).filter(|(predicted, expected)| highest(*predicted) == highest(*expected)).count()
+}
+
+/// Accuracy of a quantized network next to the float one, in percent of the samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizationReport {
+    pub granularity: Granularity,
+    pub bits: u32,
+    pub samples: usize,
+    pub float_accuracy: f32,
+    pub quantized_accuracy: f32,
+    pub float_bytes: usize,
+    pub quantized_bytes: usize
+}
+
+impl QuantizationReport {
+    /// Percentage points lost by quantizing, negative when it got better
+    pub fn accuracy_drop(&self) -> f32 {
+        self.float_accuracy - self.quantized_accuracy
+    }
+}
+
+impl fmt::Display for QuantizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "Int{} per {:?} on {} samples: {}% right instead of {}%, a drop of {} points, {} bytes instead of {}",
+            self.bits, self.granularity, self.samples, self.quantized_accuracy, self.float_accuracy, self.accuracy_drop(), self.quantized_bytes, self.float_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{LayerConfig, Workspace, FakeQuantization, samples};
+
+    fn train(mut nn: Box<NN>) -> Box<NN> {
+        let (inputs, outputs) = batch(&samples());
+        let mut workspace = Workspace::new(&nn);
+
+        for _ in 0..20 {
+            nn.train_batch(&mut workspace, &inputs, &outputs, 0.5).unwrap();
+        }
+
+        nn
+    }
+
+    fn configs() -> [LayerConfig; 2] {
+        [LayerConfig::new(6, ActivationFunction::Tanh), LayerConfig::new(2, ActivationFunction::Sigmoid)]
+    }
+
+    fn trained(quantization: FakeQuantization) -> Box<NN> {
+        train(NN::from_seed(3, &configs(), 5).with_quantization(quantization))
+    }
+
+    fn trained_float() -> Box<NN> {
+        train(NN::from_seed(3, &configs(), 5))
+    }
+
+    #[test]
+    fn calibrated_ranges_cover_every_value() {
+        let nn = trained_float();
+        let samples = samples();
+        let ranges = ranges(&nn, &samples[..16]);
+
+        let (inputs, _) = batch(&samples[..16]);
+        let mut values = inputs.into_dyn();
+        let mut ranges = ranges.iter();
+
+        for layer in nn.layers.iter() {
+            if let LayerKind::Dense(_) = layer {
+                let &(min, max) = ranges.next().unwrap();
+
+                assert!(min <= 0.0 && max >= 0.0, "Zero not in {min}..{max}");
+                assert!(values.iter().all(|&value| (min..=max).contains(&value)), "{values} not in {min}..{max}");
+            }
+
+            values = layer.evaluate(values);
+        }
+
+        assert!(ranges.next().is_none());
+    }
+
+    #[test]
+    fn integer_forward_pass_matches_the_float_one() {
+        let nn = trained_float();
+        let samples = samples();
+        let (inputs, _) = batch(&samples);
+
+        for granularity in [Granularity::Layer, Granularity::Channel] {
+            let quantized = nn.quantize_int8(&samples, granularity);
+            let difference = (&nn.predict_batch(&inputs) - &quantized.predict_batch(&inputs)).mapv(f32::abs);
+
+            assert!(difference.iter().all(|&difference| difference < 1e-2), "{granularity:?}: {difference}");
+            assert_eq!(quantized.predict(&samples[3].input), quantized.predict_batch(&inputs).row(3).to_vec());
+        }
+    }
+
+    #[test]
+    fn channels_get_their_own_scales() {
+        let nn = trained_float();
+        let samples = samples();
+
+        let layer = nn.quantize_int8(&samples, Granularity::Layer);
+        let channel = nn.quantize_int8(&samples, Granularity::Channel);
+
+        for (layer, channel) in layer.layers.iter().zip(&channel.layers) {
+            assert!(layer.scale_w.iter().all(|&scale| scale == layer.scale_w[0]));
+            assert!(channel.scale_w.iter().any(|&scale| scale != channel.scale_w[0]), "{}", channel.scale_w);
+
+            // the biggest weight of the layer sets its scale, every neuron has one at most as big
+            assert!(channel.scale_w.iter().all(|&scale| scale <= layer.scale_w[0]));
+            assert!(channel.scale_w.iter().any(|&scale| scale == layer.scale_w[0]));
+        }
+    }
+
+    #[test]
+    fn nothing_to_export_before_training() {
+        let nn: Box<NN> = NN::with_seed(&[3, 4, 2], 3).with_quantization(FakeQuantization::int8());
+        assert!(nn.export_quantized().is_none());
+
+        let nn: Box<NN> = NN::with_seed(&[3, 4, 2], 3);
+        assert!(nn.export_quantized().is_none());
+    }
+
+    #[test]
+    fn exported_network_computes_the_fake_quantized_forward_pass() {
+        for quantization in [FakeQuantization::int8(), FakeQuantization::new(8, Granularity::Layer), FakeQuantization::int4()] {
+            let nn = trained(quantization);
+            let quantized = nn.export_quantized().unwrap();
+
+            let (inputs, _) = batch(&samples());
+            let difference = (&nn.predict_batch(&inputs) - &quantized.predict_batch(&inputs)).mapv(f32::abs);
+
+            // only the biases are rounded further, to the scale of the accumulator
+            assert!(difference.iter().all(|&difference| difference < 1e-3), "{quantization:?}: {difference}");
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Serialize, Deserialize};
+use ndarray::{Array, ArrayBase, Data, Dimension};
+
+use super::{Float, sequential::ParameterKind};
+
+/// Penalizes big weights, l1 * sum(|w|) + l2 / 2 * sum(w^2)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Regularization {
+    pub l1: f32,
+    pub l2: f32,
+    /// Whether the biases are penalized as well as the weights
+    pub bias: bool
+}
+
+impl Regularization {
+    pub fn l1(l1: f32) -> Self {
+        Self { l1, ..Default::default() }
+    }
+
+    pub fn l2(l2: f32) -> Self {
+        Self { l2, ..Default::default() }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.l1 == 0.0 && self.l2 == 0.0
+    }
+
+    /// Weights are always penalized, biases only when asked for, the other parameters never
+    pub fn applies(&self, kind: ParameterKind) -> bool {
+        match kind {
+            ParameterKind::Weight => true,
+            ParameterKind::Bias => self.bias,
+            ParameterKind::Other => false
+        }
+    }
+
+    pub fn penalty<F: Float, S: Data<Elem = F>, D: Dimension>(&self, values: &ArrayBase<S, D>) -> F {
+        if self.is_none() {
+            return F::zero()
+        }
+
+        let (l1, l2) = (F::of(self.l1.into()), F::of(self.l2.into()));
+
+        values.fold(F::zero(), |total, &value| {
+            total + l1 * value.abs() + l2 / F::of(2.0) * value * value
+        })
+    }
+
+    /// Adds the derivative of the penalty to the gradient
+    pub fn add_gradient<F: Float, S: Data<Elem = F>, D: Dimension>(&self, values: &ArrayBase<S, D>, gradient: &mut Array<F, D>) {
+        if self.is_none() {
+            return
+        }
+
+        let (l1, l2) = (F::of(self.l1.into()), F::of(self.l2.into()));
+
+        gradient.zip_mut_with(values, |gradient, &value| {
+            let sign = if value > F::zero() {
+                F::one()
+            } else if value < F::zero() {
+                -F::one()
+            } else {
+                F::zero()
+            };
+
+            *gradient += l1 * sign + l2 * value
+        })
+    }
+}
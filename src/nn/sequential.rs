@@ -0,0 +1,563 @@
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+
+use serde::{Serialize, Deserialize};
+
+use rand::rngs::StdRng;
+use ndarray::{s, stack, Array, Array2, Array3, ArrayD, ArrayView2, ArrayViewD, ArrayViewMutD, Axis, Dimension, IxDyn};
+
+use super::{Input, Output, ActivationFunction, OptimizerFunction, Initializer, Regularization, Clipping, NNError, Mode, Norm, Normalization, Float, Workspace};
+use super::graph::Graph;
+use super::quantize::FakeQuantization;
+
+mod dense;
+pub use dense::Dense;
+
+mod activation;
+
+mod dropout;
+pub use dropout::Dropout;
+
+mod norm;
+
+mod reshape;
+pub use reshape::Reshape;
+
+mod conv;
+pub use conv::Conv2D;
+
+mod pool;
+pub use pool::{Pool, PoolKind};
+
+mod recurrent;
+pub use recurrent::{Recurrent, Cell};
+
+mod attention;
+pub use attention::Attention;
+
+mod positional;
+pub use positional::Positional;
+
+mod encoder;
+pub use encoder::Encoder;
+
+mod patch;
+pub use patch::PatchEmbedding;
+
+mod quantize;
+pub use quantize::Quantize;
+
+/// Values in row major order, so that `into_shape` keeps their meaning.
+/// Products can come out column major, when one of their axes has a single value.
+fn standard<F: Float, D: Dimension>(values: Array<F, D>) -> Array<F, D> {
+    if values.is_standard_layout() {
+        values
+    } else {
+        values.as_standard_layout().into_owned()
+    }
+}
+
+/// The gradients of every part of a layer made of other layers, given how many parameters each part has
+fn split_parts<'a, F: Float>(mut gradients: &'a mut [ArrayD<F>], lens: &[usize]) -> Vec<&'a mut [ArrayD<F>]> {
+    lens.iter().map(|&len| {
+        let (part, rest) = std::mem::take(&mut gradients).split_at_mut(len);
+        gradients = rest;
+
+        part
+    }).collect()
+}
+
+/// How the model treats a parameter when applying its gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterKind {
+    /// Regularized and pruned
+    Weight,
+    /// Regularized only when `Regularization::bias` is set
+    Bias,
+    /// Neither, like the gain of a normalization or a learned positional embedding
+    Other
+}
+
+/// A stage of a `Sequential` model, working on whole batches with the samples on the first axis
+pub trait Layer<F: Float = f32> {
+    /// Whatever the layer remembers between `forward` and `backward`
+    type Cache: Debug + Clone + Default + Send;
+
+    /// Whatever a recurrent layer carries from one call to the next, the default starts over
+    type State: Debug + Clone + Default + Send;
+
+    /// Shape of one output sample given the shape of one input sample
+    fn output_shape(&self, input: &[usize]) -> Vec<usize>;
+
+    /// Feeds a batch through the layer, keeping in `cache` what `backward` needs.
+    /// `mode` and `rng` are only used by layers that act differently while training.
+    fn forward(&self, input: ArrayD<F>, cache: &mut Self::Cache, mode: Mode, rng: &mut StdRng) -> ArrayD<F>;
+
+    /// Feeds a batch through the layer for inference, remembering nothing
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F>;
+
+    /// Like `forward`, but recurrent layers start from `state` and leave their last state in it
+    fn forward_state(&self, input: ArrayD<F>, cache: &mut Self::Cache, _state: &mut Self::State, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        self.forward(input, cache, mode, rng)
+    }
+
+    /// Like `evaluate`, carrying `state` the same way as `forward_state`
+    fn evaluate_state(&self, input: ArrayD<F>, _state: &mut Self::State) -> ArrayD<F> {
+        self.evaluate(input)
+    }
+
+    /// Error of the input given the error of the output of the last `forward`,
+    /// adding the gradients of the parameters over the batch to `gradients`, in the order of `parameters`
+    fn backward(&self, error: ArrayD<F>, cache: &Self::Cache, gradients: &mut [ArrayD<F>]) -> ArrayD<F>;
+
+    /// Learnable parameters, updated by the model
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        Vec::new()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        Vec::new()
+    }
+
+    /// How the model treats every parameter, in the order of `parameters`
+    fn kinds(&self) -> Vec<ParameterKind> {
+        Vec::new()
+    }
+
+    /// Keeps track of the statistics of a training batch split over `caches`, after its gradients were applied
+    fn track(&mut self, _caches: &[&Self::Cache]) { }
+
+    /// Rounds the weights and values like `quantization` from now on, or stops with `None`
+    fn set_quantization(&mut self, _quantization: Option<FakeQuantization>) { }
+}
+
+/// Big layers are boxed with their caches, so that every kind of layer and of cache takes little space
+impl<F: Float, L: Layer<F>> Layer<F> for Box<L> {
+    type Cache = Box<L::Cache>;
+    type State = L::State;
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        (**self).output_shape(input)
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut Box<L::Cache>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        (**self).forward(input, cache, mode, rng)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        (**self).evaluate(input)
+    }
+
+    fn forward_state(&self, input: ArrayD<F>, cache: &mut Box<L::Cache>, state: &mut L::State, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        (**self).forward_state(input, cache, state, mode, rng)
+    }
+
+    fn evaluate_state(&self, input: ArrayD<F>, state: &mut L::State) -> ArrayD<F> {
+        (**self).evaluate_state(input, state)
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &Box<L::Cache>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        (**self).backward(error, cache, gradients)
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        (**self).parameters()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        (**self).parameters_mut()
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        (**self).kinds()
+    }
+
+    fn track(&mut self, caches: &[&Box<L::Cache>]) {
+        let caches: Vec<&L::Cache> = caches.iter().map(|cache| &***cache).collect();
+
+        (**self).track(&caches)
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        (**self).set_quantization(quantization)
+    }
+}
+
+/// The cache or state of a layer of kind `$kind`, replacing one of another kind
+macro_rules! fit {
+    ($slot:expr, $enum:ident::$kind:ident) => {{
+        if !matches!($slot, $enum::$kind(_)) {
+            *$slot = $enum::$kind(Default::default());
+        }
+
+        match $slot {
+            $enum::$kind(inner) => inner,
+            _ => unreachable!()
+        }
+    }};
+}
+
+/// Every kind of layer a model can hold, with the caches and states that go with them
+macro_rules! layer_kinds {
+    ($($kind:ident($layer:ty)),* $(,)?) => {
+        /// Every kind of layer a model can hold, so that it can be serialized
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        pub enum LayerKind<F: Float = f32> {
+            $($kind($layer)),*
+        }
+
+        /// Cache of any kind of layer, empty before its first `forward`
+        #[derive(Debug, Clone, Default)]
+        pub enum CacheKind<F: Float = f32> {
+            #[default]
+            Empty,
+            $($kind(<$layer as Layer<F>>::Cache)),*
+        }
+
+        /// State of any kind of layer, empty to start over
+        #[derive(Debug, Clone, Default)]
+        pub enum StateKind<F: Float = f32> {
+            #[default]
+            Empty,
+            $($kind(<$layer as Layer<F>>::State)),*
+        }
+
+        impl<F: Float> Layer<F> for LayerKind<F> {
+            type Cache = CacheKind<F>;
+            type State = StateKind<F>;
+
+            fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::output_shape(layer, input)),*
+                }
+            }
+
+            fn forward(&self, input: ArrayD<F>, cache: &mut CacheKind<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::forward(layer, input, fit!(cache, CacheKind::$kind), mode, rng)),*
+                }
+            }
+
+            fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::evaluate(layer, input)),*
+                }
+            }
+
+            fn forward_state(&self, input: ArrayD<F>, cache: &mut CacheKind<F>, state: &mut StateKind<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::forward_state(layer, input, fit!(cache, CacheKind::$kind), fit!(state, StateKind::$kind), mode, rng)),*
+                }
+            }
+
+            fn evaluate_state(&self, input: ArrayD<F>, state: &mut StateKind<F>) -> ArrayD<F> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::evaluate_state(layer, input, fit!(state, StateKind::$kind))),*
+                }
+            }
+
+            fn backward(&self, error: ArrayD<F>, cache: &CacheKind<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+                match (self, cache) {
+                    $((Self::$kind(layer), CacheKind::$kind(cache)) => <$layer as Layer<F>>::backward(layer, error, cache, gradients),)*
+                    _ => panic!("Backward before forward")
+                }
+            }
+
+            fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::parameters(layer)),*
+                }
+            }
+
+            fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::parameters_mut(layer)),*
+                }
+            }
+
+            fn kinds(&self) -> Vec<ParameterKind> {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::kinds(layer)),*
+                }
+            }
+
+            /// Caches of another kind, left by a batch that never reached the layer, are skipped
+            fn track(&mut self, caches: &[&CacheKind<F>]) {
+                match self {
+                    $(Self::$kind(layer) => {
+                        let caches: Vec<_> = caches.iter().filter_map(|cache| match cache {
+                            CacheKind::$kind(cache) => Some(cache),
+                            _ => None
+                        }).collect();
+
+                        <$layer as Layer<F>>::track(layer, &caches)
+                    }),*
+                }
+            }
+
+            fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+                match self {
+                    $(Self::$kind(layer) => <$layer as Layer<F>>::set_quantization(layer, quantization)),*
+                }
+            }
+        }
+    };
+}
+
+layer_kinds! {
+    Dense(Dense<F>),
+    Activation(ActivationFunction),
+    Dropout(Dropout),
+    Norm(Norm<F>),
+    Reshape(Reshape),
+    Conv2D(Conv2D<F>),
+    Pool(Pool),
+    Recurrent(Recurrent<F>),
+    Attention(Box<Attention<F>>),
+    Positional(Positional<F>),
+    Encoder(Box<Encoder<F>>),
+    PatchEmbedding(PatchEmbedding<F>),
+    Quantize(Quantize<F>)
+}
+
+/// Recurrent state of a `Sequential` model fed one step at a time with `Sequential::step`
+#[derive(Debug, Clone)]
+pub struct Stream<F: Float = f32> {
+    states: Vec<StateKind<F>>
+}
+
+impl<F: Float> Stream<F> {
+    pub fn new(model: &Sequential<F>) -> Self {
+        Self { states: vec![StateKind::default(); model.layers.len()] }
+    }
+
+    /// Forgets the steps so far, the next one starts a new sequence
+    pub fn reset(&mut self) {
+        self.states.fill(StateKind::default());
+    }
+}
+
+/// One sequence, a row of inputs and of expected outputs for every step
+#[derive(Debug, Clone)]
+pub struct Sequence<F: Float = f32> {
+    pub inputs: Array2<F>,
+    pub outputs: Array2<F>
+}
+
+/// Layers stacked one after the other, each feeding the next: a `Graph` that is a chain.
+/// Training, scoring and everything else that isn't about chaining layers or sequences comes from the graph.
+/// Samples are flat, they are reshaped to the input shape before the first layer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Sequential<F: Float = f32> {
+    graph: Graph<F>
+}
+
+impl<F: Float> Deref for Sequential<F> {
+    type Target = Graph<F>;
+
+    fn deref(&self) -> &Graph<F> {
+        &self.graph
+    }
+}
+
+impl<F: Float> DerefMut for Sequential<F> {
+    fn deref_mut(&mut self) -> &mut Graph<F> {
+        &mut self.graph
+    }
+}
+
+impl<F: Float> Sequential<F> {
+    /// Empty model, add layers with `push` or the builder methods
+    pub fn new(input_shape: &[usize]) -> Self {
+        Self::with_seed(input_shape, rand::random())
+    }
+
+    pub fn with_seed(input_shape: &[usize], seed: u64) -> Self {
+        let mut graph = Graph::with_seed(seed);
+        graph.input(input_shape);
+
+        Self { graph }
+    }
+
+    /// Plain multilayer perceptron, dense layers activated by `activation`
+    pub fn mlp(arch: &[usize], activation: ActivationFunction) -> Self {
+        assert!(arch.len() > 1, "Invalid arch");
+
+        let mut model = Self::new(&arch[..1]);
+
+        for size in arch[1..].iter() {
+            model = model.dense(*size, Initializer::for_activation(activation)).activation(activation);
+        }
+
+        model
+    }
+
+    /// Adds a layer at the end of the chain with `build`, given the current end
+    fn chain(mut self, build: impl FnOnce(&mut Graph<F>, usize) -> usize) -> Self {
+        let output = self.graph.output;
+        build(&mut self.graph, output);
+
+        self
+    }
+
+    pub fn push(self, layer: LayerKind<F>) -> Self {
+        self.chain(|graph, output| graph.layer(layer, output))
+    }
+
+    /// Fully connected layer, the biases start at 0
+    pub fn dense(self, size: usize, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.dense(output, size, initializer))
+    }
+
+    /// A softmax only makes sense as a probability distribution, it switches the loss to cross entropy
+    pub fn activation(self, activation: ActivationFunction) -> Self {
+        self.chain(|graph, output| graph.activation(output, activation))
+    }
+
+    pub fn dropout(self, rate: f32) -> Self {
+        self.chain(|graph, output| graph.dropout(output, rate))
+    }
+
+    pub fn norm(self, norm: Normalization) -> Self {
+        self.chain(|graph, output| graph.norm(output, norm))
+    }
+
+    pub fn reshape(self, shape: &[usize]) -> Self {
+        self.chain(|graph, output| graph.reshape(output, shape))
+    }
+
+    /// Flattens (channels, height, width) samples before dense layers
+    pub fn flatten(self) -> Self {
+        self.chain(|graph, output| graph.flatten(output))
+    }
+
+    /// Convolution with `channels` kernels of size `kernel`, the biases start at 0
+    pub fn conv2d(self, channels: usize, kernel: (usize, usize), stride: usize, padding: usize, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.conv2d(output, channels, kernel, stride, padding, initializer))
+    }
+
+    /// Recurrent layer of `size` hidden neurons over (time, features) inputs,
+    /// outputting every step when `sequences` is set and only the last one otherwise
+    pub fn recurrent(self, cell: Cell, size: usize, sequences: bool, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.recurrent(output, cell, size, sequences, initializer))
+    }
+
+    /// Multi-head self-attention over (time, features) inputs, a digit reshaped to (28, 28) is a sequence of its rows
+    pub fn attention(self, heads: usize, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.attention(output, heads, initializer))
+    }
+
+    /// Learned embedding of the position of every step of (time, features) inputs
+    pub fn positional(self, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.positional(output, initializer))
+    }
+
+    /// Fixed sinusoidal embedding of the position of every step of (time, features) inputs
+    pub fn sinusoidal(self) -> Self {
+        self.chain(|graph, output| graph.sinusoidal(output))
+    }
+
+    /// Transformer encoder block of `heads` attention heads and a feed forward stage of `hidden` neurons
+    pub fn encoder(self, heads: usize, hidden: usize, activation: ActivationFunction) -> Self {
+        self.chain(|graph, output| graph.encoder(output, heads, hidden, activation))
+    }
+
+    /// Embeds every `size` x `size` patch of (channels, height, width) inputs into `features`, giving (patches, features) sequences
+    pub fn patch_embedding(self, size: usize, features: usize, initializer: Initializer) -> Self {
+        self.chain(|graph, output| graph.patch_embedding(output, size, features, initializer))
+    }
+
+    /// Rounds the values going through like `FakeQuantization` once the model is quantized, see `Graph::quantize`
+    pub fn quantize(self) -> Self {
+        self.chain(|graph, output| graph.quantize(output))
+    }
+
+    pub fn max_pool(self, size: usize, stride: usize) -> Self {
+        self.chain(|graph, output| graph.max_pool(output, size, stride))
+    }
+
+    pub fn avg_pool(self, size: usize, stride: usize) -> Self {
+        self.chain(|graph, output| graph.avg_pool(output, size, stride))
+    }
+
+    pub fn with_optimizer(mut self, optimizer: OptimizerFunction) -> Self {
+        self.set_optimizer(optimizer);
+        self
+    }
+
+    pub fn with_regularization(mut self, regularization: Regularization) -> Self {
+        self.regularization = regularization;
+        self
+    }
+
+    pub fn with_clipping(mut self, clipping: Clipping) -> Self {
+        self.clipping = clipping;
+        self
+    }
+
+    pub fn with_quantization(mut self, quantization: FakeQuantization) -> Self {
+        self.set_quantization(Some(quantization));
+        self
+    }
+
+    /// Shape of one input sample
+    pub fn input_shape(&self) -> &[usize] {
+        &self.inputs[0]
+    }
+
+    /// Output of the next step of the sequence fed to `stream` so far
+    pub fn step(&self, stream: &mut Stream<F>, input: &Input<F>) -> Output<F> {
+        // a batch of one sequence, one step long
+        let mut shape = vec![1, 1];
+        shape.extend_from_slice(&self.input_shape()[1..]);
+
+        let values = input.clone().into_dyn().into_shape(IxDyn(&shape)).expect("Input layers not of same size!");
+
+        self.evaluate_states(vec![values], &mut stream.states).iter().copied().collect()
+    }
+
+    /// Trains on sequences of the same length with truncated backpropagation through time:
+    /// every window of `truncation` steps is one update, starting from the states the window before left.
+    /// The loss of every step counts, the gradients are averaged over the steps of the window.
+    pub fn train_sequences(&mut self, workspace: &mut Workspace<F>, sequences: &[Sequence<F>], rate: F, truncation: usize) -> Result<(), NNError> {
+        assert!(truncation > 0, "Truncation must be at least 1 step");
+
+        let (inputs, outputs) = sequence_batch(sequences);
+        let length = inputs.shape()[1];
+
+        workspace.clear_states();
+
+        for start in (0..length).step_by(truncation) {
+            let end = length.min(start + truncation);
+
+            let inputs = inputs.slice(s![.., start..end, ..]).to_owned().into_dyn();
+            let outputs = outputs.slice(s![.., start..end, ..]).to_owned().into_dyn();
+
+            self.train_split(workspace, vec![inputs], &outputs, rate, sequences.len() * (end - start))?;
+        }
+
+        Ok(())
+    }
+
+    /// Average loss of every step of the sequences, fed whole
+    pub fn score_sequences(&self, sequences: &[Sequence<F>]) -> F {
+        let (inputs, outputs) = sequence_batch(sequences);
+        let steps = inputs.shape()[0] * inputs.shape()[1];
+
+        self.error(vec![inputs.into_dyn()], &outputs.into_dyn()) / F::of(steps as f64)
+    }
+}
+
+/// Sequences of the same length as (sequence, step, value) inputs and outputs
+fn sequence_batch<F: Float>(sequences: &[Sequence<F>]) -> (Array3<F>, Array3<F>) {
+    assert!(!sequences.is_empty(), "No sequences");
+
+    let length = sequences[0].inputs.nrows();
+    assert!(sequences.iter().all(|sequence| sequence.inputs.nrows() == length && sequence.outputs.nrows() == length), "Sequences not of the same length");
+
+    let inputs: Vec<ArrayView2<F>> = sequences.iter().map(|sequence| sequence.inputs.view()).collect();
+    let outputs: Vec<ArrayView2<F>> = sequences.iter().map(|sequence| sequence.outputs.view()).collect();
+
+    (stack(Axis(0), &inputs).expect("Inputs of the same size"), stack(Axis(0), &outputs).expect("Outputs of the same size"))
+}
@@ -0,0 +1,30 @@
+use rand::rngs::StdRng;
+use ndarray::ArrayD;
+
+use super::{Layer, Mode, ActivationFunction, Float};
+
+/// The cache is the input and the activated values
+impl<F: Float> Layer<F> for ActivationFunction {
+    type Cache = (ArrayD<F>, ArrayD<F>);
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut (ArrayD<F>, ArrayD<F>), _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let activated = self.activate(&input);
+
+        *cache = (input, activated.clone());
+
+        activated
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.activate(&input)
+    }
+
+    fn backward(&self, error: ArrayD<F>, (unscaled, activated): &(ArrayD<F>, ArrayD<F>), _gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        self.derivate(unscaled, activated, &error)
+    }
+}
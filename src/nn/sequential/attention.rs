@@ -0,0 +1,230 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{s, Array2, Array3, Array4, ArrayD, ArrayViewD, ArrayViewMutD, Ix3};
+
+use super::{Layer, ParameterKind, Mode, Dense, ActivationFunction, Initializer, Float, FakeQuantization, split_parts};
+
+/// Multi-head scaled dot-product self-attention over (time, features) samples.
+/// Every head attends with its own slice of the projected features, every step sees every other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attention<F: Float = f32> {
+    pub heads: usize,
+    pub query: Dense<F>,
+    pub key: Dense<F>,
+    pub value: Dense<F>,
+    /// Mixes the heads back together
+    pub output: Dense<F>
+}
+
+/// What backward needs: the caches of the dense layers, the projections and the weights of every head
+#[derive(Debug, Clone, Default)]
+pub struct AttentionCache<F: Float> {
+    /// In the order of `Attention::parts`
+    parts: [Array2<F>; 4],
+    query: Array3<F>,
+    key: Array3<F>,
+    value: Array3<F>,
+    /// (batch, heads, time, time)
+    weights: Array4<F>
+}
+
+fn sequences<F: Float>(values: ArrayD<F>) -> Array3<F> {
+    values.into_dimensionality::<Ix3>().expect("Attention needs (time, features) samples")
+}
+
+impl<F: Float> Attention<F> {
+    pub fn new(features: usize, heads: usize, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        assert!(heads > 0 && features.is_multiple_of(heads), "{features} features can't be split in {heads} heads");
+
+        let mut dense = || Dense::new(features, features, initializer, Initializer::Zeros, rng);
+
+        Self {
+            heads,
+            query: dense(),
+            key: dense(),
+            value: dense(),
+            output: dense()
+        }
+    }
+
+    fn parts(&self) -> [&Dense<F>; 4] {
+        [&self.query, &self.key, &self.value, &self.output]
+    }
+
+    fn parts_mut(&mut self) -> [&mut Dense<F>; 4] {
+        [&mut self.query, &mut self.key, &mut self.value, &mut self.output]
+    }
+
+    /// Attention weights of every head (batch, heads, time, time), and the heads joined
+    fn attend(&self, query: &Array3<F>, key: &Array3<F>, value: &Array3<F>) -> (Array4<F>, Array3<F>) {
+        let (batch, time, features) = query.dim();
+        let size = features / self.heads;
+        let scale = F::one() / F::of(size as f64).sqrt();
+
+        let mut scores = Array4::zeros((batch, self.heads, time, time));
+
+        for sample in 0..batch {
+            for head in 0..self.heads {
+                let columns = head * size..(head + 1) * size;
+                let query = query.slice(s![sample, .., columns.clone()]);
+                let key = key.slice(s![sample, .., columns]);
+
+                scores.slice_mut(s![sample, head, .., ..]).assign(&(query.dot(&key.t()) * scale));
+            }
+        }
+
+        let weights = ActivationFunction::Softmax.activate(&scores);
+        let mut joined = Array3::zeros((batch, time, features));
+
+        for sample in 0..batch {
+            for head in 0..self.heads {
+                let columns = head * size..(head + 1) * size;
+                let attended = weights.slice(s![sample, head, .., ..]).dot(&value.slice(s![sample, .., columns.clone()]));
+
+                joined.slice_mut(s![sample, .., columns]).assign(&attended);
+            }
+        }
+
+        (weights, joined)
+    }
+}
+
+impl<F: Float> Layer<F> for Attention<F> {
+    type Cache = AttentionCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert_eq!(input.len(), 2, "Attention needs (time, features) samples, got {input:?}");
+        assert_eq!(input[1], self.query.value_w.ncols(), "Attention over {} features, got {input:?}", self.query.value_w.ncols());
+
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut AttentionCache<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        let [query_cache, key_cache, value_cache, output_cache] = &mut cache.parts;
+
+        let query = sequences(self.query.forward(input.clone(), query_cache, mode, rng));
+        let key = sequences(self.key.forward(input.clone(), key_cache, mode, rng));
+        let value = sequences(self.value.forward(input, value_cache, mode, rng));
+
+        let (weights, joined) = self.attend(&query, &key, &value);
+        let output = self.output.forward(joined.into_dyn(), output_cache, mode, rng);
+
+        cache.query = query;
+        cache.key = key;
+        cache.value = value;
+        cache.weights = weights;
+
+        output
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        let query = sequences(self.query.evaluate(input.clone()));
+        let key = sequences(self.key.evaluate(input.clone()));
+        let value = sequences(self.value.evaluate(input));
+
+        self.output.evaluate(self.attend(&query, &key, &value).1.into_dyn())
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &AttentionCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let AttentionCache { parts, query, key, value, weights } = cache;
+        let lens = self.parts().map(|part| part.parameters().len());
+        let [query_gradients, key_gradients, value_gradients, output_gradients]: [&mut [ArrayD<F>]; 4] = split_parts(gradients, &lens).try_into().expect("One part per dense layer");
+
+        let error = sequences(self.output.backward(error, &parts[3], output_gradients));
+
+        let (batch, time, features) = query.dim();
+        let size = features / self.heads;
+        let scale = F::one() / F::of(size as f64).sqrt();
+
+        let mut error_weights = Array4::zeros(weights.raw_dim());
+        let mut error_value = Array3::zeros(value.raw_dim());
+
+        for sample in 0..batch {
+            for head in 0..self.heads {
+                let columns = head * size..(head + 1) * size;
+                let error = error.slice(s![sample, .., columns.clone()]);
+                let weights = weights.slice(s![sample, head, .., ..]);
+
+                error_weights.slice_mut(s![sample, head, .., ..]).assign(&error.dot(&value.slice(s![sample, .., columns.clone()]).t()));
+                error_value.slice_mut(s![sample, .., columns]).assign(&weights.t().dot(&error));
+            }
+        }
+
+        // through the softmax, then the scaled products of the queries and keys
+        let error_scores = ActivationFunction::Softmax.derivate(weights, weights, &error_weights) * scale;
+
+        let mut error_query = Array3::zeros((batch, time, features));
+        let mut error_key = Array3::zeros((batch, time, features));
+
+        for sample in 0..batch {
+            for head in 0..self.heads {
+                let columns = head * size..(head + 1) * size;
+                let error = error_scores.slice(s![sample, head, .., ..]);
+
+                error_query.slice_mut(s![sample, .., columns.clone()]).assign(&error.dot(&key.slice(s![sample, .., columns.clone()])));
+                error_key.slice_mut(s![sample, .., columns.clone()]).assign(&error.t().dot(&query.slice(s![sample, .., columns])));
+            }
+        }
+
+        let mut error = self.query.backward(error_query.into_dyn(), &parts[0], query_gradients);
+        error += &self.key.backward(error_key.into_dyn(), &parts[1], key_gradients);
+        error += &self.value.backward(error_value.into_dyn(), &parts[2], value_gradients);
+
+        error
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        self.parts().into_iter().flat_map(|part| part.parameters()).collect()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        self.parts_mut().into_iter().flat_map(|part| part.parameters_mut()).collect()
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        self.parts().into_iter().flat_map(|part| part.kinds()).collect()
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        for part in self.parts_mut() {
+            part.set_quantization(quantization);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array1};
+
+    use crate::nn::{Sequential, ActivationFunction, Initializer};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+
+    #[test]
+    fn every_step_is_attended_the_same_whatever_its_position() {
+        let model: Sequential = Sequential::with_seed(&[4, 6], 1).attention(2, Initializer::GlorotUniform);
+        let input = Array1::from_shape_fn(24, |j| ((j * 5) % 7) as f32 / 7.0 - 0.5);
+
+        // the last step moved to the front
+        let mut moved = input.clone();
+        moved.slice_mut(s![..6]).assign(&input.slice(s![18..]));
+        moved.slice_mut(s![6..]).assign(&input.slice(s![..18]));
+
+        let (output, moved) = (model.predict(&input), model.predict(&moved));
+
+        for (a, b) in output.iter().zip(moved[6..].iter().chain(moved[..6].iter())) {
+            assert!((a - b).abs() < 1e-5, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn gradients_of_every_head() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[4, 6], 1)
+            .attention(3, Initializer::GlorotUniform)
+            .flatten()
+            .dense(3, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Softmax);
+
+        assert_gradients(&mut model, &one_hot_samples(4 * 6, 3), 1e-7);
+    }
+}
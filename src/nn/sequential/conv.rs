@@ -0,0 +1,244 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{Array1, Array2, Array4, ArrayD, ArrayViewD, ArrayViewMutD, Axis, Ix4, linalg::general_mat_mul};
+
+use super::{Layer, ParameterKind, Mode, Initializer, Float, FakeQuantization, standard};
+
+/// 2D convolution over samples of shape (channels, height, width),
+/// every output channel has its own kernel over all input channels and a bias
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conv2D<F: Float = f32> {
+    /// Shape (output channels, input channels, kernel height, kernel width)
+    pub value_w: Array4<F>,
+    pub bias_b: Array1<F>,
+    pub stride: usize,
+    /// Zeros added on every side of the input
+    pub padding: usize,
+    /// The kernels are rounded in both passes, per output channel with `Granularity::Channel`
+    #[serde(default)]
+    pub quantization: Option<FakeQuantization>
+}
+
+/// Size of the output along one axis
+fn output_len(input: usize, kernel: usize, stride: usize, padding: usize) -> usize {
+    assert!(input + 2 * padding >= kernel, "Kernel bigger than the padded input");
+
+    (input + 2 * padding - kernel) / stride + 1
+}
+
+impl<F: Float> Conv2D<F> {
+    pub fn new(in_channels: usize, out_channels: usize, kernel: (usize, usize), stride: usize, padding: usize, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        assert!(stride > 0, "Stride must be at least 1");
+
+        // every output sees in_channels * kernel values
+        let fan_in = in_channels * kernel.0 * kernel.1;
+        let value_w = standard(initializer.weights(out_channels, fan_in, rng))
+            .into_shape((out_channels, in_channels, kernel.0, kernel.1))
+            .expect("Kernel of the right size");
+
+        Self {
+            value_w,
+            bias_b: Array1::zeros(out_channels),
+            stride,
+            padding,
+            quantization: None
+        }
+    }
+
+    fn kernel(&self) -> (usize, usize, usize, usize) {
+        self.value_w.dim()
+    }
+
+    /// Kernels as rows, (output channels, input channels * kernel height * kernel width), rounded with fake quantization
+    fn rows(&self) -> Array2<F> {
+        let (out_channels, in_channels, kernel_h, kernel_w) = self.kernel();
+        let rows = self.value_w.view().into_shape((out_channels, in_channels * kernel_h * kernel_w)).expect("Kernel in standard layout").to_owned();
+
+        match &self.quantization {
+            Some(quantization) => quantization.weights(&rows),
+            None => rows
+        }
+    }
+
+    /// Every patch the kernels see as one row, (batch * out height * out width, input channels * kernel height * kernel width)
+    fn patches(&self, input: &Array4<F>) -> Array2<F> {
+        let (batch, channels, height, width) = input.dim();
+        let (_, _, kernel_h, kernel_w) = self.kernel();
+        let out_h = output_len(height, kernel_h, self.stride, self.padding);
+        let out_w = output_len(width, kernel_w, self.stride, self.padding);
+
+        let mut patches = Array2::zeros((batch * out_h * out_w, channels * kernel_h * kernel_w));
+
+        for ((row, column), value) in patches.indexed_iter_mut() {
+            let (sample, out_y, out_x) = (row / (out_h * out_w), row / out_w % out_h, row % out_w);
+            let (channel, kernel_y, kernel_x) = (column / (kernel_h * kernel_w), column / kernel_w % kernel_h, column % kernel_w);
+
+            // padding is left at 0
+            let y = (out_y * self.stride + kernel_y).checked_sub(self.padding).filter(|&y| y < height);
+            let x = (out_x * self.stride + kernel_x).checked_sub(self.padding).filter(|&x| x < width);
+
+            if let (Some(y), Some(x)) = (y, x) {
+                *value = input[(sample, channel, y, x)];
+            }
+        }
+
+        patches
+    }
+
+    /// Sums the error of every patch back into the input it came from
+    fn unpatch(&self, patches: &Array2<F>, shape: (usize, usize, usize, usize)) -> Array4<F> {
+        let (_, _, height, width) = shape;
+        let (_, _, kernel_h, kernel_w) = self.kernel();
+        let out_h = output_len(height, kernel_h, self.stride, self.padding);
+        let out_w = output_len(width, kernel_w, self.stride, self.padding);
+
+        let mut input = Array4::zeros(shape);
+
+        for ((row, column), value) in patches.indexed_iter() {
+            let (sample, out_y, out_x) = (row / (out_h * out_w), row / out_w % out_h, row % out_w);
+            let (channel, kernel_y, kernel_x) = (column / (kernel_h * kernel_w), column / kernel_w % kernel_h, column % kernel_w);
+
+            let y = (out_y * self.stride + kernel_y).checked_sub(self.padding).filter(|&y| y < height);
+            let x = (out_x * self.stride + kernel_x).checked_sub(self.padding).filter(|&x| x < width);
+
+            if let (Some(y), Some(x)) = (y, x) {
+                input[(sample, channel, y, x)] += *value;
+            }
+        }
+
+        input
+    }
+
+    fn convolve(&self, input: ArrayD<F>) -> (Array4<F>, Array2<F>, (usize, usize, usize, usize)) {
+        let input = input.into_dimensionality::<Ix4>().expect("Convolutions need (channels, height, width) samples");
+        let (batch, channels, height, width) = input.dim();
+        let (out_channels, in_channels, kernel_h, kernel_w) = self.kernel();
+
+        assert_eq!(channels, in_channels, "Input channels not of same size!");
+
+        let out_h = output_len(height, kernel_h, self.stride, self.padding);
+        let out_w = output_len(width, kernel_w, self.stride, self.padding);
+
+        // (batch * out_h * out_w, in) . (in, out_channels)
+        let patches = self.patches(&input);
+        let unscaled = patches.dot(&self.rows().t()) + &self.bias_b;
+
+        let output = standard(unscaled).into_shape((batch, out_h, out_w, out_channels)).expect("Output of the right size")
+            .permuted_axes([0, 3, 1, 2])
+            .as_standard_layout()
+            .into_owned();
+
+        (output, patches, input.dim())
+    }
+}
+
+/// What backward needs of the batch a convolution was fed
+#[derive(Debug, Clone, Default)]
+pub struct ConvCache<F: Float> {
+    /// Every patch the kernels saw, one per row
+    patches: Array2<F>,
+    /// Shape of the input
+    shape: (usize, usize, usize, usize)
+}
+
+impl<F: Float> Layer<F> for Conv2D<F> {
+    type Cache = ConvCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert_eq!(input.len(), 3, "Convolutions need (channels, height, width) samples, got {input:?}");
+
+        let (out_channels, _, kernel_h, kernel_w) = self.kernel();
+
+        vec![
+            out_channels,
+            output_len(input[1], kernel_h, self.stride, self.padding),
+            output_len(input[2], kernel_w, self.stride, self.padding)
+        ]
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut ConvCache<F>, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let (output, patches, shape) = self.convolve(input);
+
+        *cache = ConvCache { patches, shape };
+
+        output.into_dyn()
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.convolve(input).0.into_dyn()
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &ConvCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        // one row per output position, like the patches
+        let error = error.into_dimensionality::<Ix4>().expect("Error of a convolution");
+        let (batch, out_channels, out_h, out_w) = error.dim();
+        let error = error.permuted_axes([0, 2, 3, 1]).as_standard_layout().into_owned()
+            .into_shape((batch * out_h * out_w, out_channels)).expect("Error of the right size");
+
+        let (gradient_w, gradient_b) = gradients.split_at_mut(1);
+        let (_, in_channels, kernel_h, kernel_w) = self.kernel();
+        let mut gradient_w = gradient_w[0].view_mut()
+            .into_shape((out_channels, in_channels * kernel_h * kernel_w)).expect("Gradient of another layer");
+
+        // (out_channels x rows) . (rows x in)
+        general_mat_mul(F::one(), &error.t(), &cache.patches, F::one(), &mut gradient_w);
+        gradient_b[0] += &error.sum_axis(Axis(0));
+
+        self.unpatch(&error.dot(&self.rows()), cache.shape).into_dyn()
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        vec![self.value_w.view().into_dyn(), self.bias_b.view().into_dyn()]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![self.value_w.view_mut().into_dyn(), self.bias_b.view_mut().into_dyn()]
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Weight, ParameterKind::Bias]
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.quantization = quantization;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array, Array1, Array4};
+
+    use super::*;
+    use crate::nn::{Sequential, ActivationFunction};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+
+    fn ones(stride: usize, padding: usize) -> Conv2D {
+        Conv2D { value_w: Array4::ones((1, 1, 2, 2)), bias_b: Array1::zeros(1), stride, padding, quantization: None }
+    }
+
+    #[test]
+    fn sums_every_window() {
+        let input = Array::range(1.0, 10.0, 1.0).into_shape((1, 1, 3, 3)).unwrap().into_dyn();
+
+        let output = ones(1, 0).evaluate(input.clone());
+        assert_eq!(output.iter().copied().collect::<Vec<f32>>(), [12.0, 16.0, 24.0, 28.0]);
+
+        // the padding is 0, the first window only sees the corner
+        let output = ones(2, 1).evaluate(input);
+        assert_eq!(output.shape(), [1, 1, 2, 2]);
+        assert_eq!(output.iter().copied().collect::<Vec<f32>>(), [1.0, 5.0, 11.0, 28.0]);
+    }
+
+    #[test]
+    fn gradients_with_stride_and_padding() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[2, 5, 4], 1)
+            .conv2d(3, (3, 2), 2, 1, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Tanh)
+            .flatten()
+            .dense(3, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Softmax);
+
+        assert_gradients(&mut model, &one_hot_samples(2 * 5 * 4, 3), 1e-7);
+    }
+}
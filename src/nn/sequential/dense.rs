@@ -0,0 +1,118 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{Array1, Array2, ArrayD, ArrayViewD, ArrayViewMutD, Axis, CowArray, Ix2, IxDyn, linalg::general_mat_mul};
+
+use super::{Layer, ParameterKind, Mode, Initializer, Float, FakeQuantization, standard};
+
+/// Fully connected layer, z = x . w^T + b. Every step of a sequence is fed on its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dense<F: Float = f32> {
+    /// Shape (outputs, inputs)
+    pub value_w: Array2<F>,
+    pub bias_b: Array1<F>,
+    /// The weights are rounded in both passes, their gradient goes straight through to the float ones
+    #[serde(default)]
+    pub quantization: Option<FakeQuantization>
+}
+
+impl<F: Float> Dense<F> {
+    pub fn new(inputs: usize, outputs: usize, weights: Initializer, bias: Initializer, rng: &mut impl Rng) -> Self {
+        let value_w = weights.weights(outputs, inputs, rng);
+        let bias_b = bias.bias(outputs, inputs, rng);
+
+        Self::from_parameters(value_w, bias_b)
+    }
+
+    pub fn from_parameters(value_w: Array2<F>, bias_b: Array1<F>) -> Self {
+        assert_eq!(value_w.nrows(), bias_b.len(), "Not as many biases as outputs");
+
+        Self { value_w, bias_b, quantization: None }
+    }
+
+    /// Weights as the forward pass sees them, rounded with fake quantization
+    pub fn weights(&self) -> CowArray<'_, F, Ix2> {
+        match &self.quantization {
+            Some(quantization) => quantization.weights(&self.value_w).into(),
+            None => self.value_w.view().into()
+        }
+    }
+}
+
+/// One row per sample, or per step of every sequence
+fn flat<F: Float>(values: ArrayD<F>) -> Array2<F> {
+    if values.ndim() == 2 {
+        return values.into_dimensionality::<Ix2>().expect("Dense layers need flat inputs")
+    }
+
+    let size = values.shape()[values.ndim() - 1];
+    let rows = values.len() / size.max(1);
+
+    standard(values).into_shape((rows, size)).expect("Dense layers need flat inputs")
+}
+
+/// Rows back to the leading axes of `shape`
+fn unflat<F: Float>(values: Array2<F>, shape: &[usize]) -> ArrayD<F> {
+    let mut shape = shape.to_vec();
+    *shape.last_mut().expect("Rows without columns") = values.ncols();
+
+    standard(values).into_shape(IxDyn(&shape)).expect("Rows of the right size")
+}
+
+/// The cache is the input as rows
+impl<F: Float> Layer<F> for Dense<F> {
+    type Cache = Array2<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        let mut shape = input.to_vec();
+        shape[input.len() - 1] = self.value_w.nrows();
+
+        shape
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut Array2<F>, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let shape = input.shape().to_vec();
+        let input = flat(input);
+        let unscaled = input.dot(&self.weights().t()) + &self.bias_b;
+
+        *cache = input;
+
+        unflat(unscaled, &shape)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        let shape = input.shape().to_vec();
+
+        unflat(flat(input).dot(&self.weights().t()) + &self.bias_b, &shape)
+    }
+
+    fn backward(&self, error: ArrayD<F>, input: &Array2<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let shape = error.shape().to_vec();
+        let error = flat(error);
+
+        let (gradient_w, gradient_b) = gradients.split_at_mut(1);
+        let mut gradient_w = gradient_w[0].view_mut().into_dimensionality::<Ix2>().expect("Gradient of another layer");
+
+        // (outputs x batch) . (batch x inputs)
+        general_mat_mul(F::one(), &error.t(), input, F::one(), &mut gradient_w);
+        gradient_b[0] += &error.sum_axis(Axis(0));
+
+        unflat(error.dot(&self.weights()), &shape)
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        vec![self.value_w.view().into_dyn(), self.bias_b.view().into_dyn()]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![self.value_w.view_mut().into_dyn(), self.bias_b.view_mut().into_dyn()]
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Weight, ParameterKind::Bias]
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.quantization = quantization;
+    }
+}
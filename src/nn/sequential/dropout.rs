@@ -0,0 +1,59 @@
+use serde::{Serialize, Deserialize};
+use rand::rngs::StdRng;
+use ndarray::{ArrayD, Ix2};
+
+use super::{Layer, Mode, Float};
+use super::super::dropout;
+
+/// Drops a fraction of the values while training, inverted so evaluation needs no scaling
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dropout {
+    pub rate: f32
+}
+
+impl Dropout {
+    pub fn new(rate: f32) -> Self {
+        assert!((0.0..1.0).contains(&rate), "Invalid dropout rate {rate}, should be in 0..1");
+
+        Self { rate }
+    }
+}
+
+/// The cache is the mask, `None` when nothing was dropped
+impl<F: Float> Layer<F> for Dropout {
+    type Cache = Option<ArrayD<F>>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut Option<ArrayD<F>>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        if mode == Mode::Eval || self.rate == 0.0 {
+            *cache = None;
+
+            return input
+        }
+
+        // any shape is dropped as one flat row per sample
+        let samples = input.shape()[0];
+        let dim = Ix2(samples, input.len() / samples.max(1));
+        let mask = dropout::mask(dim, self.rate, rng).into_shape(input.raw_dim()).expect("Mask of the same size");
+
+        let dropped = input * &mask;
+        *cache = Some(mask);
+
+        dropped
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        input
+    }
+
+    fn backward(&self, error: ArrayD<F>, mask: &Option<ArrayD<F>>, _gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        match mask {
+            Some(mask) => error * mask,
+            None => error
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{Array2, ArrayD, ArrayViewD, ArrayViewMutD, IxDyn};
+
+use super::{Layer, ParameterKind, Mode, Attention, Dense, Norm, ActivationFunction, Initializer, Float, FakeQuantization, standard, split_parts};
+use super::attention::AttentionCache;
+use super::super::LayerNorm;
+use super::super::normalization::NormCache;
+
+/// Transformer encoder block over (time, features) samples, normalized before each stage:
+/// x + attention(norm(x)), then x + feed(norm(x)) where feed is two dense layers applied to every step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encoder<F: Float = f32> {
+    pub norm_attention: Norm<F>,
+    pub attention: Attention<F>,
+    pub norm_feed: Norm<F>,
+    pub hidden: Dense<F>,
+    pub activation: ActivationFunction,
+    pub output: Dense<F>
+}
+
+/// The caches of every stage
+#[derive(Debug, Clone, Default)]
+pub struct EncoderCache<F: Float> {
+    norm_attention: NormCache<F>,
+    attention: AttentionCache<F>,
+    norm_feed: NormCache<F>,
+    hidden: Array2<F>,
+    activation: (ArrayD<F>, ArrayD<F>),
+    output: Array2<F>
+}
+
+/// Every step of every sample as one row, for the normalizations
+fn by_step<F: Float>(values: ArrayD<F>, f: impl FnOnce(ArrayD<F>) -> ArrayD<F>) -> ArrayD<F> {
+    let shape = values.shape().to_vec();
+    let size = shape[shape.len() - 1];
+    let rows = values.len() / size.max(1);
+
+    let values = standard(values).into_shape(IxDyn(&[rows, size])).expect("Steps of the right size");
+
+    standard(f(values)).into_shape(IxDyn(&shape)).expect("Steps of the right size")
+}
+
+impl<F: Float> Encoder<F> {
+    pub fn new(features: usize, heads: usize, hidden: usize, activation: ActivationFunction, rng: &mut impl Rng) -> Self {
+        let layer_norm = || Norm::Layer(LayerNorm::new(features, 1e-5));
+
+        Self {
+            norm_attention: layer_norm(),
+            attention: Attention::new(features, heads, Initializer::GlorotUniform, rng),
+            norm_feed: layer_norm(),
+            hidden: Dense::new(features, hidden, Initializer::for_activation(activation), Initializer::Zeros, rng),
+            activation,
+            output: Dense::new(hidden, features, Initializer::GlorotUniform, Initializer::Zeros, rng)
+        }
+    }
+}
+
+/// The parameters of the stages in order, the activation has none
+impl<F: Float> Layer<F> for Encoder<F> {
+    type Cache = EncoderCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        self.attention.output_shape(input)
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut EncoderCache<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        let normalized = by_step(input.clone(), |values| Layer::forward(&self.norm_attention, values, &mut cache.norm_attention, mode, rng));
+        let values = input + self.attention.forward(normalized, &mut cache.attention, mode, rng);
+
+        let normalized = by_step(values.clone(), |values| Layer::forward(&self.norm_feed, values, &mut cache.norm_feed, mode, rng));
+        let hidden = self.hidden.forward(normalized, &mut cache.hidden, mode, rng);
+        let hidden = self.activation.forward(hidden, &mut cache.activation, mode, rng);
+
+        values + self.output.forward(hidden, &mut cache.output, mode, rng)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        let normalized = by_step(input.clone(), |values| Layer::evaluate(&self.norm_attention, values));
+        let values = input + self.attention.evaluate(normalized);
+
+        let normalized = by_step(values.clone(), |values| Layer::evaluate(&self.norm_feed, values));
+        let hidden = self.activation.evaluate(self.hidden.evaluate(normalized));
+
+        values + self.output.evaluate(hidden)
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &EncoderCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let lens = [
+            Layer::parameters(&self.norm_attention).len(),
+            self.attention.parameters().len(),
+            Layer::parameters(&self.norm_feed).len(),
+            self.hidden.parameters().len(),
+            self.output.parameters().len()
+        ];
+        let [norm_attention, attention, norm_feed, hidden, output]: [&mut [ArrayD<F>]; 5] = split_parts(gradients, &lens).try_into().expect("One part per stage");
+
+        // the residual connections pass the error through untouched, next to each stage
+        let feed = self.output.backward(error.clone(), &cache.output, output);
+        let feed = self.activation.backward(feed, &cache.activation, &mut []);
+        let feed = self.hidden.backward(feed, &cache.hidden, hidden);
+        let error = error + by_step(feed, |feed| Layer::backward(&self.norm_feed, feed, &cache.norm_feed, norm_feed));
+
+        let attended = self.attention.backward(error.clone(), &cache.attention, attention);
+
+        error + by_step(attended, |attended| Layer::backward(&self.norm_attention, attended, &cache.norm_attention, norm_attention))
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        [
+            Layer::parameters(&self.norm_attention),
+            self.attention.parameters(),
+            Layer::parameters(&self.norm_feed),
+            self.hidden.parameters(),
+            self.output.parameters()
+        ].into_iter().flatten().collect()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        [
+            Layer::parameters_mut(&mut self.norm_attention),
+            self.attention.parameters_mut(),
+            Layer::parameters_mut(&mut self.norm_feed),
+            self.hidden.parameters_mut(),
+            self.output.parameters_mut()
+        ].into_iter().flatten().collect()
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        [
+            Layer::kinds(&self.norm_attention),
+            self.attention.kinds(),
+            Layer::kinds(&self.norm_feed),
+            self.hidden.kinds(),
+            self.output.kinds()
+        ].into_iter().flatten().collect()
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.attention.set_quantization(quantization);
+        self.hidden.set_quantization(quantization);
+        self.output.set_quantization(quantization);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nn::{Sequential, ActivationFunction, Initializer};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+
+    #[test]
+    fn gradients_through_both_stages() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[4, 6], 1)
+            .encoder(2, 8, ActivationFunction::Gelu)
+            .flatten()
+            .dense(3, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Softmax);
+
+        assert_gradients(&mut model, &one_hot_samples(4 * 6, 3), 1e-6);
+    }
+}
@@ -0,0 +1,79 @@
+use rand::rngs::StdRng;
+use ndarray::{Array2, ArrayD, ArrayViewD, ArrayViewMutD, Ix2};
+
+use super::{Layer, ParameterKind, Mode, Norm, Float};
+use super::super::normalization::NormCache;
+
+fn flat<F: Float>(values: ArrayD<F>) -> Array2<F> {
+    values.into_dimensionality::<Ix2>().expect("Normalization needs flat inputs")
+}
+
+/// Batch statistics are only used while training, the running ones are updated
+/// with those of every training batch once its gradients are applied
+impl<F: Float> Layer<F> for Norm<F> {
+    type Cache = NormCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut NormCache<F>, mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let (normalized, norm_cache) = Norm::forward(self, &flat(input), mode == Mode::Train);
+
+        *cache = norm_cache;
+
+        normalized.into_dyn()
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        Norm::evaluate(self, &flat(input)).into_dyn()
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &NormCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        Norm::backward(self, cache, &flat(error), gradients).into_dyn()
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        Norm::parameters(self)
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        Norm::parameters_mut(self)
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Other, ParameterKind::Other]
+    }
+
+    fn track(&mut self, caches: &[&NormCache<F>]) {
+        Norm::track(self, caches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use crate::nn::{Sequential, Workspace, Normalization, Norm, Initializer};
+    use crate::nn::sequential::LayerKind;
+
+    #[test]
+    fn running_statistics_are_tracked_apart_from_the_gradients() {
+        let mut model: Sequential = Sequential::with_seed(&[3], 1).dense(4, Initializer::GlorotUniform).norm(Normalization::batch());
+        let mut workspace = Workspace::new(&model);
+
+        let inputs = Array2::from_shape_fn((8, 3), |(i, j)| (i * 3 + j) as f32 / 10.0).into_dyn();
+        let outputs = Array2::zeros((8, 4)).into_dyn();
+
+        // only the gain and the bias are learned
+        assert_eq!(workspace.gradients[1].len(), 2);
+
+        model.train_batch(&mut workspace, inputs, &outputs, 0.1).unwrap();
+
+        let LayerKind::Norm(Norm::Batch(norm)) = &model.layers[1] else { panic!("Not a batch normalization") };
+
+        assert!(norm.running_mean.iter().any(|&mean| mean != 0.0));
+        assert!(norm.running_var.iter().any(|&var| var != 1.0));
+    }
+}
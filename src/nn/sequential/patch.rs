@@ -0,0 +1,140 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{Array2, Array4, ArrayD, ArrayViewD, ArrayViewMutD, Ix4};
+
+use super::{Layer, ParameterKind, Mode, Initializer, Float, FakeQuantization, Dense, standard};
+
+/// Cuts (channels, height, width) samples into size x size patches and embeds every one of them
+/// with the same dense layer, giving (patches, features) sequences for attention like a vision transformer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchEmbedding<F: Float = f32> {
+    /// Patches are size x size, the height and width of the samples are multiples of it
+    pub size: usize,
+    /// From the channels * size * size values of a patch to its features
+    pub dense: Dense<F>
+}
+
+impl<F: Float> PatchEmbedding<F> {
+    pub fn new(channels: usize, size: usize, features: usize, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        assert!(size > 0, "Patches must be at least 1 x 1");
+
+        Self {
+            size,
+            dense: Dense::new(channels * size * size, features, initializer, Initializer::Zeros, rng)
+        }
+    }
+
+    /// (batch, patches, channels * size * size), the patches in row-major order
+    fn patches(&self, input: Array4<F>) -> ArrayD<F> {
+        let (batch, channels, height, width) = input.dim();
+        let (rows, columns) = (height / self.size, width / self.size);
+
+        let patches = standard(input).into_shape((batch, channels, rows, self.size, columns, self.size)).expect("Samples cut in whole patches")
+            .permuted_axes([0, 2, 4, 1, 3, 5]);
+
+        standard(patches).into_shape((batch, rows * columns, channels * self.size * self.size)).expect("Patches of the right size").into_dyn()
+    }
+
+    /// The error of every patch back to where it was cut from
+    fn unpatch(&self, patches: ArrayD<F>, shape: (usize, usize, usize, usize)) -> ArrayD<F> {
+        let (batch, channels, height, width) = shape;
+        let (rows, columns) = (height / self.size, width / self.size);
+
+        let input = standard(patches).into_shape((batch, rows, columns, channels, self.size, self.size)).expect("Patches of the right size")
+            .permuted_axes([0, 3, 1, 4, 2, 5]);
+
+        standard(input).into_shape(shape).expect("Samples of the right size").into_dyn()
+    }
+}
+
+fn samples<F: Float>(values: ArrayD<F>) -> Array4<F> {
+    values.into_dimensionality::<Ix4>().expect("Patch embeddings need (channels, height, width) samples")
+}
+
+/// What backward needs of the batch patches were cut from
+#[derive(Debug, Clone, Default)]
+pub struct PatchCache<F: Float> {
+    /// Cache of the dense layer, the patches as rows
+    dense: Array2<F>,
+    /// Shape of the input
+    shape: (usize, usize, usize, usize)
+}
+
+impl<F: Float> Layer<F> for PatchEmbedding<F> {
+    type Cache = PatchCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert!(input.len() == 3 && input[1].is_multiple_of(self.size) && input[2].is_multiple_of(self.size), "Patch embeddings need (channels, height, width) samples cut in whole {0} x {0} patches, got {input:?}", self.size);
+        assert_eq!(input[0] * self.size * self.size, self.dense.value_w.ncols(), "Patch embedding of another number of channels");
+
+        vec![(input[1] / self.size) * (input[2] / self.size), self.dense.value_w.nrows()]
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut PatchCache<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        let input = samples(input);
+        cache.shape = input.dim();
+
+        self.dense.forward(self.patches(input), &mut cache.dense, mode, rng)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.dense.evaluate(self.patches(samples(input)))
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &PatchCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let error = self.dense.backward(error, &cache.dense, gradients);
+
+        self.unpatch(error, cache.shape)
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        self.dense.parameters()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        self.dense.parameters_mut()
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        self.dense.kinds()
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.dense.set_quantization(quantization);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array, Array1};
+
+    use super::*;
+    use crate::nn::{Sequential, ActivationFunction};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+
+    #[test]
+    fn patches_go_back_where_they_were_cut() {
+        let input = Array::range(0.0, 32.0, 1.0).into_shape((1, 2, 4, 4)).unwrap();
+        let embedding = PatchEmbedding::<f32> { size: 2, dense: Dense::from_parameters(Array2::zeros((1, 8)), Array1::zeros(1)) };
+
+        let patches = embedding.patches(input.clone());
+
+        assert_eq!(patches.shape(), [1, 4, 8]);
+        // the top right patch of both channels
+        assert_eq!(patches.slice(ndarray::s![0, 1, ..]).to_vec(), [2.0, 3.0, 6.0, 7.0, 18.0, 19.0, 22.0, 23.0]);
+        assert_eq!(embedding.unpatch(patches, (1, 2, 4, 4)), input.into_dyn());
+    }
+
+    #[test]
+    fn gradients_through_the_patches() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[2, 4, 6], 1)
+            .patch_embedding(2, 5, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Tanh)
+            .flatten()
+            .dense(3, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Softmax);
+
+        assert_gradients(&mut model, &one_hot_samples(2 * 4 * 6, 3), 1e-7);
+    }
+}
@@ -0,0 +1,189 @@
+use std::cmp::Ordering;
+
+use serde::{Serialize, Deserialize};
+use rand::rngs::StdRng;
+use ndarray::{Array4, ArrayD, Ix4};
+
+use super::{Layer, Mode, Float};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PoolKind {
+    #[default]
+    Max,
+    Average
+}
+
+/// Downsamples every channel of (channels, height, width) samples, window by window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pool {
+    pub kind: PoolKind,
+    /// Windows are size x size
+    pub size: usize,
+    pub stride: usize
+}
+
+impl Pool {
+    pub fn new(kind: PoolKind, size: usize, stride: usize) -> Self {
+        assert!(size > 0 && stride > 0, "Invalid pool of size {size} and stride {stride}");
+
+        Self { kind, size, stride }
+    }
+
+    pub fn max(size: usize) -> Self {
+        Self::new(PoolKind::Max, size, size)
+    }
+
+    pub fn average(size: usize) -> Self {
+        Self::new(PoolKind::Average, size, size)
+    }
+
+    fn output_len(&self, input: usize) -> usize {
+        assert!(input >= self.size, "Pool bigger than the input");
+
+        (input - self.size) / self.stride + 1
+    }
+
+    /// Pooled values, with the flat index in the input of the maximum of every window
+    fn pool<F: Float>(&self, input: &Array4<F>) -> (Array4<F>, Option<Array4<usize>>) {
+        let (batch, channels, height, width) = input.dim();
+        let (out_h, out_w) = (self.output_len(height), self.output_len(width));
+
+        let mut output = Array4::zeros((batch, channels, out_h, out_w));
+        let mut indices = match self.kind {
+            PoolKind::Max => Some(Array4::zeros(output.raw_dim())),
+            PoolKind::Average => None
+        };
+
+        for ((sample, channel, out_y, out_x), value) in output.indexed_iter_mut() {
+            let window = (0..self.size).flat_map(|dy| (0..self.size).map(move |dx| (out_y * self.stride + dy, out_x * self.stride + dx)));
+
+            match &mut indices {
+                Some(indices) => {
+                    let (y, x) = window.max_by(|a, b| input[(sample, channel, a.0, a.1)].partial_cmp(&input[(sample, channel, b.0, b.1)]).unwrap_or(Ordering::Equal)).expect("Empty window");
+
+                    *value = input[(sample, channel, y, x)];
+                    indices[(sample, channel, out_y, out_x)] = y * width + x;
+                },
+                None => {
+                    *value = window.map(|(y, x)| input[(sample, channel, y, x)]).sum::<F>() / F::of((self.size * self.size) as f64);
+                }
+            }
+        }
+
+        (output, indices)
+    }
+}
+
+fn samples<F: Float>(values: ArrayD<F>) -> Array4<F> {
+    values.into_dimensionality::<Ix4>().expect("Pools need (channels, height, width) samples")
+}
+
+/// What backward needs of the batch a pool was fed
+#[derive(Debug, Clone, Default)]
+pub struct PoolCache {
+    /// Shape of the input
+    shape: (usize, usize, usize, usize),
+    /// Flat index in the input of the maximum of every window, only for max pooling
+    indices: Option<Array4<usize>>
+}
+
+impl<F: Float> Layer<F> for Pool {
+    type Cache = PoolCache;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert_eq!(input.len(), 3, "Pools need (channels, height, width) samples, got {input:?}");
+
+        vec![input[0], self.output_len(input[1]), self.output_len(input[2])]
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut PoolCache, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let input = samples(input);
+        let (output, indices) = self.pool(&input);
+
+        *cache = PoolCache { shape: input.dim(), indices };
+
+        output.into_dyn()
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.pool(&samples(input)).0.into_dyn()
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &PoolCache, _gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let (_, _, _, width) = cache.shape;
+        let error = samples(error);
+
+        let mut input = Array4::zeros(cache.shape);
+
+        for ((sample, channel, out_y, out_x), value) in error.indexed_iter() {
+            match &cache.indices {
+                // only the maximum got through
+                Some(indices) => {
+                    let index = indices[(sample, channel, out_y, out_x)];
+
+                    input[(sample, channel, index / width, index % width)] += *value;
+                },
+                None => {
+                    let value = *value / F::of((self.size * self.size) as f64);
+
+                    for dy in 0..self.size {
+                        for dx in 0..self.size {
+                            input[(sample, channel, out_y * self.stride + dy, out_x * self.stride + dx)] += value;
+                        }
+                    }
+                }
+            }
+        }
+
+        input.into_dyn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use ndarray::{Array, Array4};
+
+    use super::*;
+
+    /// Output of a pool and the error of its input for an error of 1 on every output
+    fn pool(pool: Pool, input: Array4<f32>) -> (ArrayD<f32>, ArrayD<f32>) {
+        let mut cache = PoolCache::default();
+        let output = pool.forward(input.into_dyn(), &mut cache, Mode::Train, &mut StdRng::seed_from_u64(0));
+        let error = pool.backward(ArrayD::ones(output.raw_dim()), &cache, &mut []);
+
+        (output, error)
+    }
+
+    #[test]
+    fn max_pool_passes_the_error_to_the_maximums() {
+        let input = Array::from_shape_vec((1, 1, 4, 4), vec![
+            1.0, 2.0, 0.0, 0.0,
+            3.0, 4.0, 0.0, 9.0,
+            5.0, 0.0, 1.0, 1.0,
+            0.0, 0.0, 7.0, 1.0
+        ]).unwrap();
+
+        let (output, error) = pool(Pool::max(2), input);
+
+        assert_eq!(output.iter().copied().collect::<Vec<f32>>(), [4.0, 9.0, 5.0, 7.0]);
+        assert_eq!(error.iter().copied().collect::<Vec<f32>>(), [
+            0.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 1.0,
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0
+        ]);
+    }
+
+    #[test]
+    fn average_pool_spreads_the_error_over_overlapping_windows() {
+        let input = Array::range(0.0, 9.0, 1.0).into_shape((1, 1, 3, 3)).unwrap();
+
+        let (output, error) = pool(Pool::new(PoolKind::Average, 2, 1), input);
+
+        assert_eq!(output.iter().copied().collect::<Vec<f32>>(), [2.0, 3.0, 5.0, 6.0]);
+        // the center is in every window
+        assert_eq!(error.iter().copied().collect::<Vec<f32>>(), [0.25, 0.5, 0.25, 0.5, 1.0, 0.5, 0.25, 0.5, 0.25]);
+    }
+}
@@ -0,0 +1,185 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{s, Array2, ArrayD, ArrayViewD, ArrayViewMutD, Axis};
+
+use super::{Layer, ParameterKind, Mode, Initializer, Float};
+
+/// Adds an embedding of its position to every step of (time, features) samples,
+/// so that attention, which sees the steps as a set, knows their order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Positional<F: Float = f32> {
+    /// Shape (time, features)
+    pub value_p: Array2<F>,
+    /// Fixed sinusoidal embeddings are never trained
+    pub learned: bool
+}
+
+impl<F: Float> Positional<F> {
+    /// Embeddings learned like any weight
+    pub fn new(length: usize, features: usize, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        Self {
+            value_p: initializer.weights(length, features, rng),
+            learned: true
+        }
+    }
+
+    /// Sines and cosines of decreasing frequencies, like in the original transformer
+    pub fn sinusoidal(length: usize, features: usize) -> Self {
+        let value_p = Array2::from_shape_fn((length, features), |(position, feature)| {
+            let angle = position as f64 / 10000f64.powf((feature - feature % 2) as f64 / features as f64);
+
+            F::of(if feature % 2 == 0 { angle.sin() } else { angle.cos() })
+        });
+
+        Self { value_p, learned: false }
+    }
+
+    /// Adds the embeddings of the steps after the first `offset` ones of the sequence
+    fn embed(&self, input: ArrayD<F>, offset: usize) -> ArrayD<F> {
+        let (time, length) = (input.shape()[1], self.value_p.nrows());
+        assert!(offset + time <= length, "Sequence of {} steps longer than the {length} positional embeddings", offset + time);
+
+        input + self.value_p.slice(s![offset..offset + time, ..])
+    }
+}
+
+/// The state is how many steps of the sequence were fed so far, so that windows of truncated
+/// backpropagation and single steps get the embeddings of their own positions
+impl<F: Float> Layer<F> for Positional<F> {
+    /// The position of the first step of the batch
+    type Cache = usize;
+    type State = usize;
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert!(input.len() == 2 && input[0] <= self.value_p.nrows() && input[1] == self.value_p.ncols(), "Positional embeddings of shape {:?}, got {input:?}", self.value_p.shape());
+
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut usize, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        self.forward_state(input, cache, &mut 0, mode, rng)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.embed(input, 0)
+    }
+
+    fn forward_state(&self, input: ArrayD<F>, cache: &mut usize, state: &mut usize, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        *cache = *state;
+
+        self.evaluate_state(input, state)
+    }
+
+    fn evaluate_state(&self, input: ArrayD<F>, state: &mut usize) -> ArrayD<F> {
+        let time = input.shape()[1];
+        let output = self.embed(input, *state);
+
+        *state += time;
+
+        output
+    }
+
+    fn backward(&self, error: ArrayD<F>, offset: &usize, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        if self.learned {
+            let time = error.shape()[1];
+
+            let mut gradient = gradients[0].slice_axis_mut(Axis(0), (*offset..*offset + time).into());
+            gradient += &error.sum_axis(Axis(0));
+        }
+
+        error
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        if self.learned {
+            vec![self.value_p.view().into_dyn()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        if self.learned {
+            vec![self.value_p.view_mut().into_dyn()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        if self.learned {
+            vec![ParameterKind::Other]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array1, Array2};
+
+    use crate::nn::{Sequential, Workspace, ActivationFunction, Initializer};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+    use crate::nn::sequential::{Cell, LayerKind, Sequence, Stream};
+
+    fn model() -> Sequential {
+        Sequential::with_seed(&[6, 4], 1)
+            .positional(Initializer::GlorotUniform)
+            .recurrent(Cell::Rnn, 5, true, Initializer::GlorotUniform)
+            .dense(2, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Sigmoid)
+    }
+
+    fn embeddings(model: &Sequential) -> Array2<f32> {
+        let LayerKind::Positional(positional) = &model.layers[0] else { panic!("Not positional embeddings") };
+
+        positional.value_p.clone()
+    }
+
+    #[test]
+    fn steps_get_the_embeddings_of_their_position() {
+        let model = model();
+        let input = Array1::from_shape_fn(24, |j| (j % 5) as f32 / 5.0 - 0.3);
+
+        let mut stream = Stream::new(&model);
+        let stepped: Vec<f32> = (0..6).flat_map(|t| model.step(&mut stream, &input.slice(s![t * 4..t * 4 + 4]).to_owned())).collect();
+
+        for (a, b) in model.predict(&input).iter().zip(stepped.iter()) {
+            assert!((a - b).abs() < 1e-6, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn truncated_windows_train_the_embeddings_of_their_position() {
+        let mut model = model();
+        let mut workspace = Workspace::new(&model);
+        let before = embeddings(&model);
+
+        let sequences: Vec<Sequence> = (0..3).map(|i| Sequence {
+            inputs: Array2::from_shape_fn((6, 4), |(t, j)| ((t + i + j) % 3) as f32 / 3.0),
+            outputs: Array2::from_shape_fn((6, 2), |(t, j)| ((t + i + j) % 2) as f32)
+        }).collect();
+
+        // windows of 4 then 2 steps
+        model.train_sequences(&mut workspace, &sequences, 0.5, 4).unwrap();
+
+        let after = embeddings(&model);
+
+        for position in 0..6 {
+            assert_ne!(before.row(position), after.row(position), "Embedding {position} not trained");
+        }
+    }
+
+    #[test]
+    fn gradients_of_learned_embeddings() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[4, 3], 1)
+            .positional(Initializer::GlorotUniform)
+            .activation(ActivationFunction::Tanh)
+            .flatten()
+            .dense(3, Initializer::GlorotUniform)
+            .activation(ActivationFunction::Softmax);
+
+        assert_gradients(&mut model, &one_hot_samples(4 * 3, 3), 1e-7);
+    }
+}
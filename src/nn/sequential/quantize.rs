@@ -0,0 +1,100 @@
+use serde::{Serialize, Deserialize};
+use rand::rngs::StdRng;
+use ndarray::ArrayD;
+
+use super::{Layer, Mode, Float, FakeQuantization};
+use super::super::quantize::{range, straight_through};
+
+/// Rounds the values going through like they will be once quantized, in their range over the batch while training
+/// and in the running range tracked over the training batches otherwise. Does nothing without quantization.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quantize<F: Float = f32> {
+    #[serde(default)]
+    pub quantization: Option<FakeQuantization>,
+    /// Smallest and biggest value, `None` before the first training batch
+    #[serde(default)]
+    pub range: Option<(F, F)>
+}
+
+/// What backward and tracking need, empty without quantization
+#[derive(Debug, Clone, Default)]
+pub struct QuantizeCache<F: Float> {
+    input: ArrayD<F>,
+    /// Range the values were rounded in
+    used: Option<(F, F)>,
+    /// Range of the batch, to track
+    batch: Option<(F, F)>
+}
+
+impl<F: Float> QuantizeCache<F> {
+    /// Range the values were rounded in, `None` when they went through untouched
+    pub fn used(&self) -> Option<(F, F)> {
+        self.used
+    }
+}
+
+impl<F: Float> Quantize<F> {
+    pub fn new(quantization: Option<FakeQuantization>) -> Self {
+        Self { quantization, range: None }
+    }
+}
+
+impl<F: Float> Layer<F> for Quantize<F> {
+    type Cache = QuantizeCache<F>;
+    type State = ();
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        input.to_vec()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut QuantizeCache<F>, mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let Some(quantization) = &self.quantization else {
+            *cache = QuantizeCache::default();
+
+            return input
+        };
+
+        let batch = range(&input);
+        let used = match (mode, self.range) {
+            (Mode::Eval, Some(running)) => running,
+            _ => batch
+        };
+
+        let output = quantization.values(&input, used);
+        *cache = QuantizeCache { input, used: Some(used), batch: Some(batch) };
+
+        output
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        match (&self.quantization, self.range) {
+            (Some(quantization), Some(range)) => quantization.values(&input, range),
+            _ => input
+        }
+    }
+
+    fn backward(&self, mut error: ArrayD<F>, cache: &QuantizeCache<F>, _gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        if let Some(used) = cache.used {
+            straight_through(&mut error, &cache.input, used);
+        }
+
+        error
+    }
+
+    /// The range of a batch split over several caches covers all of them
+    fn track(&mut self, caches: &[&QuantizeCache<F>]) {
+        let Some(quantization) = &self.quantization else {
+            return
+        };
+
+        let batch = caches.iter().filter_map(|cache| cache.batch).reduce(|(min, max), (other_min, other_max)| (min.min(other_min), max.max(other_max)));
+
+        if let Some(batch) = batch {
+            self.range = Some(quantization.track(self.range, batch));
+        }
+    }
+
+    fn set_quantization(&mut self, quantization: Option<FakeQuantization>) {
+        self.quantization = quantization;
+    }
+}
@@ -0,0 +1,456 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, rngs::StdRng};
+use ndarray::{s, concatenate, stack, Array1, Array2, Array3, ArrayD, ArrayView2, ArrayViewD, ArrayViewMutD, Axis, Ix2, Ix3, Zip, linalg::general_mat_mul};
+
+use super::{Layer, ParameterKind, Mode, Initializer, Float, standard};
+use super::super::activation::sigmoid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cell {
+    /// h = tanh(x . w + h . u + b)
+    #[default]
+    #[serde(alias = "RNN")]
+    Rnn,
+    /// Input, forget, cell and output gates, carrying a cell state besides the hidden one
+    #[serde(alias = "LSTM")]
+    Lstm,
+    /// Update, reset and candidate gates
+    #[serde(alias = "GRU")]
+    Gru
+}
+
+impl Cell {
+    /// Blocks of weights, one per gate
+    fn gates(&self) -> usize {
+        match self {
+            Cell::Rnn => 1,
+            Cell::Lstm => 4,
+            Cell::Gru => 3
+        }
+    }
+}
+
+/// Recurrent layer over (time, features) samples, every step sees the hidden state of the one before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recurrent<F: Float = f32> {
+    pub cell: Cell,
+    /// Shape (gates * hidden, inputs), the gates in the order of `Cell`
+    pub value_w: Array2<F>,
+    /// Shape (gates * hidden, hidden)
+    pub value_u: Array2<F>,
+    pub bias_b: Array1<F>,
+    /// Outputs the hidden state of every step instead of only the last one
+    pub sequences: bool
+}
+
+/// What one step computed, backward needs the activated gates
+struct Step<F: Float> {
+    hidden: Array2<F>,
+    cell: Option<Array2<F>>,
+    gates: Array2<F>,
+    /// h . u of the candidate of a GRU, before the reset gate
+    recurrent: Option<Array2<F>>
+}
+
+/// Everything backward needs from every step, stacked on the first axis
+#[derive(Debug, Clone, Default)]
+pub struct RecurrentCache<F: Float> {
+    /// (batch, time, features)
+    input: Array3<F>,
+    /// The initial hidden state and the one after every step
+    hidden: Array3<F>,
+    gates: Array3<F>,
+    /// The initial cell state and the one after every step of a LSTM,
+    /// the recurrent part of the candidate of every step of a GRU
+    extra: Option<Array3<F>>
+}
+
+fn through_sigmoid<F: Float>(error: &Array2<F>, activated: ArrayView2<F>) -> Array2<F> {
+    Zip::from(error).and(activated).map_collect(|&error, &value| error * value * (F::one() - value))
+}
+
+fn through_tanh<F: Float>(error: &Array2<F>, activated: ArrayView2<F>) -> Array2<F> {
+    Zip::from(error).and(activated).map_collect(|&error, &value| error * (F::one() - value * value))
+}
+
+impl<F: Float> Recurrent<F> {
+    /// The recurrent weights start orthogonal, the forget gate of a LSTM starts open
+    pub fn new(cell: Cell, inputs: usize, size: usize, sequences: bool, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        let gates = cell.gates();
+
+        let mut bias_b = Array1::zeros(gates * size);
+        if cell == Cell::Lstm {
+            bias_b.slice_mut(s![size..2 * size]).fill(F::one());
+        }
+
+        Self {
+            cell,
+            value_w: initializer.weights(gates * size, inputs, rng),
+            value_u: Initializer::Orthogonal(1.0).weights(gates * size, size, rng),
+            bias_b,
+            sequences
+        }
+    }
+
+    /// Size of the hidden state
+    pub fn size(&self) -> usize {
+        self.value_u.ncols()
+    }
+
+    /// One step given x . w + b of the inputs
+    fn step(&self, projected: ArrayView2<F>, hidden: &Array2<F>, cell: Option<&Array2<F>>) -> Step<F> {
+        let size = self.size();
+        let recurrent = hidden.dot(&self.value_u.t());
+
+        match self.cell {
+            Cell::Rnn => {
+                let hidden = (&projected + &recurrent).mapv(F::tanh);
+
+                Step { hidden: hidden.clone(), cell: None, gates: hidden, recurrent: None }
+            },
+            Cell::Lstm => {
+                let mut gates = &projected + &recurrent;
+                gates.slice_mut(s![.., ..2 * size]).mapv_inplace(sigmoid);
+                gates.slice_mut(s![.., 2 * size..3 * size]).mapv_inplace(F::tanh);
+                gates.slice_mut(s![.., 3 * size..]).mapv_inplace(sigmoid);
+
+                let (input, forget) = (gates.slice(s![.., ..size]), gates.slice(s![.., size..2 * size]));
+                let (candidate, output) = (gates.slice(s![.., 2 * size..3 * size]), gates.slice(s![.., 3 * size..]));
+
+                let cell = &forget * cell.expect("LSTM without a cell state") + &input * &candidate;
+                let hidden = &output * &cell.mapv(F::tanh);
+
+                Step { hidden, cell: Some(cell), gates, recurrent: None }
+            },
+            Cell::Gru => {
+                let mut gates = Array2::zeros(projected.raw_dim());
+                let update_reset = &projected.slice(s![.., ..2 * size]) + &recurrent.slice(s![.., ..2 * size]);
+                gates.slice_mut(s![.., ..2 * size]).assign(&update_reset.mapv(sigmoid));
+
+                // the reset gate only scales the recurrent part of the candidate
+                let recurrent = recurrent.slice(s![.., 2 * size..]).to_owned();
+                let candidate = (&projected.slice(s![.., 2 * size..]) + &(&gates.slice(s![.., size..2 * size]) * &recurrent)).mapv(F::tanh);
+                gates.slice_mut(s![.., 2 * size..]).assign(&candidate);
+
+                let update = gates.slice(s![.., ..size]);
+                let hidden = &update * hidden + &(update.mapv(|update| F::one() - update) * &candidate);
+
+                Step { hidden, cell: None, gates, recurrent: Some(recurrent) }
+            }
+        }
+    }
+
+    /// Runs every step from `state`, leaving the last state in it. Returns the initial state and every step.
+    fn run(&self, input: ArrayD<F>, state: &mut Vec<Array2<F>>) -> (Array3<F>, Vec<Array2<F>>, Vec<Step<F>>) {
+        let input = input.into_dimensionality::<Ix3>().expect("Recurrent layers need (time, features) samples");
+        let (batch, time, features) = input.dim();
+
+        assert_eq!(features, self.value_w.ncols(), "Input layers not of same size!");
+
+        let input = standard(input);
+        let projected = input.view().into_shape((batch * time, features)).expect("Input in standard layout").dot(&self.value_w.t()) + &self.bias_b;
+        let projected = standard(projected).into_shape((batch, time, self.bias_b.len())).expect("Projection of the right size");
+
+        // an empty state starts from zeros
+        let mut initial = std::mem::take(state);
+        if initial.is_empty() {
+            let states = if self.cell == Cell::Lstm { 2 } else { 1 };
+            initial = vec![Array2::zeros((batch, self.size())); states];
+        }
+
+        let mut hidden = initial[0].clone();
+        let mut cell = initial.get(1).cloned();
+
+        assert_eq!(hidden.nrows(), batch, "State of another batch");
+
+        let steps: Vec<Step<F>> = (0..time).map(|t| {
+            let step = self.step(projected.index_axis(Axis(1), t), &hidden, cell.as_ref());
+
+            hidden = step.hidden.clone();
+            cell = step.cell.clone();
+
+            step
+        }).collect();
+
+        *state = vec![hidden];
+        state.extend(cell);
+
+        (input, initial, steps)
+    }
+
+    fn output(&self, state: &[Array2<F>], steps: &[Step<F>]) -> ArrayD<F> {
+        if !self.sequences {
+            return state[0].clone().into_dyn()
+        }
+
+        let hidden: Vec<ArrayView2<F>> = steps.iter().map(|step| step.hidden.view()).collect();
+
+        stack(Axis(1), &hidden).expect("Steps of the same size").into_dyn()
+    }
+}
+
+/// The state is the hidden state, followed by the cell state of a LSTM
+impl<F: Float> Layer<F> for Recurrent<F> {
+    type Cache = RecurrentCache<F>;
+    type State = Vec<Array2<F>>;
+
+    fn output_shape(&self, input: &[usize]) -> Vec<usize> {
+        assert_eq!(input.len(), 2, "Recurrent layers need (time, features) samples, got {input:?}");
+
+        if self.sequences {
+            vec![input[0], self.size()]
+        } else {
+            vec![self.size()]
+        }
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut RecurrentCache<F>, mode: Mode, rng: &mut StdRng) -> ArrayD<F> {
+        self.forward_state(input, cache, &mut Vec::new(), mode, rng)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        self.evaluate_state(input, &mut Vec::new())
+    }
+
+    fn forward_state(&self, input: ArrayD<F>, cache: &mut RecurrentCache<F>, state: &mut Vec<Array2<F>>, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        let (input, initial, steps) = self.run(input, state);
+
+        let mut hidden = vec![initial[0].view()];
+        hidden.extend(steps.iter().map(|step| step.hidden.view()));
+        let gates: Vec<ArrayView2<F>> = steps.iter().map(|step| step.gates.view()).collect();
+
+        let extra = match self.cell {
+            Cell::Rnn => None,
+            Cell::Lstm => {
+                let mut cells = vec![initial[1].view()];
+                cells.extend(steps.iter().filter_map(|step| step.cell.as_ref().map(|cell| cell.view())));
+
+                Some(stack(Axis(0), &cells))
+            },
+            Cell::Gru => {
+                let recurrent: Vec<ArrayView2<F>> = steps.iter().filter_map(|step| step.recurrent.as_ref().map(|recurrent| recurrent.view())).collect();
+
+                Some(stack(Axis(0), &recurrent))
+            }
+        };
+
+        let output = self.output(state, &steps);
+
+        *cache = RecurrentCache {
+            input,
+            hidden: stack(Axis(0), &hidden).expect("Steps of the same size"),
+            gates: stack(Axis(0), &gates).expect("Steps of the same size"),
+            extra: extra.map(|extra| extra.expect("Steps of the same size"))
+        };
+
+        output
+    }
+
+    fn evaluate_state(&self, input: ArrayD<F>, state: &mut Vec<Array2<F>>) -> ArrayD<F> {
+        let (_, _, steps) = self.run(input, state);
+
+        self.output(state, &steps)
+    }
+
+    fn backward(&self, error: ArrayD<F>, cache: &RecurrentCache<F>, gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        let RecurrentCache { input, hidden, gates, extra } = cache;
+
+        let (batch, time, features) = input.dim();
+        let size = self.size();
+
+        // only the last step gets an error when the layer outputs just that one
+        let error = if self.sequences {
+            error.into_dimensionality::<Ix3>().expect("Error of a sequence")
+        } else {
+            let mut errors = Array3::zeros((batch, time, size));
+            errors.index_axis_mut(Axis(1), time - 1).assign(&error.into_dimensionality::<Ix2>().expect("Error of the last step"));
+            errors
+        };
+
+        let (gradient_w, rest) = gradients.split_at_mut(1);
+        let (gradient_u, gradient_b) = rest.split_at_mut(1);
+        let mut gradient_w = gradient_w[0].view_mut().into_dimensionality::<Ix2>().expect("Gradient of another layer");
+        let mut gradient_u = gradient_u[0].view_mut().into_dimensionality::<Ix2>().expect("Gradient of another layer");
+
+        let mut input_error = Array3::zeros((batch, time, features));
+        let mut next_hidden = Array2::zeros((batch, size));
+        let mut next_cell = Array2::zeros((batch, size));
+
+        for t in (0..time).rev() {
+            let error_h = &error.index_axis(Axis(1), t) + &next_hidden;
+            let (previous, current) = (hidden.index_axis(Axis(0), t), hidden.index_axis(Axis(0), t + 1));
+            let gates = gates.index_axis(Axis(0), t);
+
+            // errors of the gates before activation, on the input side and on the recurrent side,
+            // and whatever reaches the previous hidden state without going through u
+            let (error_x, error_r, direct) = match self.cell {
+                Cell::Rnn => {
+                    let error_z = through_tanh(&error_h, current);
+
+                    (error_z.clone(), error_z, None)
+                },
+                Cell::Lstm => {
+                    let extra = extra.as_ref().expect("LSTM without cell states");
+                    let (previous_cell, cell) = (extra.index_axis(Axis(0), t), extra.index_axis(Axis(0), t + 1));
+                    let (input, forget) = (gates.slice(s![.., ..size]), gates.slice(s![.., size..2 * size]));
+                    let (candidate, output) = (gates.slice(s![.., 2 * size..3 * size]), gates.slice(s![.., 3 * size..]));
+
+                    let cell = cell.mapv(F::tanh);
+                    let error_c = through_tanh(&(&error_h * &output), cell.view()) + &next_cell;
+                    next_cell = &error_c * &forget;
+
+                    let error_z = concatenate(Axis(1), &[
+                        through_sigmoid(&(&error_c * &candidate), input).view(),
+                        through_sigmoid(&(&error_c * &previous_cell), forget).view(),
+                        through_tanh(&(&error_c * &input), candidate).view(),
+                        through_sigmoid(&(&error_h * &cell), output).view()
+                    ]).expect("Gates of the same size");
+
+                    (error_z.clone(), error_z, None)
+                },
+                Cell::Gru => {
+                    let recurrent = extra.as_ref().expect("GRU without recurrent candidates").index_axis(Axis(0), t);
+                    let (update, reset, candidate) = (gates.slice(s![.., ..size]), gates.slice(s![.., size..2 * size]), gates.slice(s![.., 2 * size..]));
+
+                    let error_n = through_tanh(&(&error_h * &update.mapv(|update| F::one() - update)), candidate);
+                    let error_u = through_sigmoid(&(&error_h * &(&previous - &candidate)), update);
+                    let error_r = through_sigmoid(&(&error_n * &recurrent), reset);
+
+                    let error_x = concatenate(Axis(1), &[error_u.view(), error_r.view(), error_n.view()]).expect("Gates of the same size");
+                    let error_recurrent = concatenate(Axis(1), &[error_u.view(), error_r.view(), (&error_n * &reset).view()]).expect("Gates of the same size");
+
+                    (error_x, error_recurrent, Some(&error_h * &update))
+                }
+            };
+
+            // (gates x batch) . (batch x inputs)
+            general_mat_mul(F::one(), &error_x.t(), &input.index_axis(Axis(1), t), F::one(), &mut gradient_w);
+            general_mat_mul(F::one(), &error_r.t(), &previous, F::one(), &mut gradient_u);
+            gradient_b[0] += &error_x.sum_axis(Axis(0));
+
+            input_error.index_axis_mut(Axis(1), t).assign(&error_x.dot(&self.value_w));
+
+            next_hidden = error_r.dot(&self.value_u);
+            if let Some(direct) = direct {
+                next_hidden += &direct;
+            }
+        }
+
+        input_error.into_dyn()
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, F>> {
+        vec![self.value_w.view().into_dyn(), self.value_u.view().into_dyn(), self.bias_b.view().into_dyn()]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![self.value_w.view_mut().into_dyn(), self.value_u.view_mut().into_dyn(), self.bias_b.view_mut().into_dyn()]
+    }
+
+    fn kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Weight, ParameterKind::Weight, ParameterKind::Bias]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::nn::{Sequential, Workspace, ActivationFunction};
+    use crate::nn::gradient_check::{one_hot_samples, assert_gradients};
+    use crate::nn::sequential::{LayerKind, StateKind, Sequence, sequence_batch};
+
+    #[test]
+    fn gradients_through_time_of_every_cell() {
+        for cell in [Cell::Rnn, Cell::Lstm, Cell::Gru] {
+            for sequences in [true, false] {
+                let mut model: Sequential<f64> = Sequential::with_seed(&[4, 3], 1).recurrent(cell, 5, sequences, Initializer::GlorotUniform);
+
+                if sequences {
+                    model = model.flatten();
+                }
+
+                let mut model = model.dense(3, Initializer::GlorotUniform).activation(ActivationFunction::Softmax);
+
+                assert_gradients(&mut model, &one_hot_samples(4 * 3, 3), 1e-7);
+            }
+        }
+    }
+
+    fn weights(model: &mut Sequential<f64>) -> &mut Array2<f64> {
+        let LayerKind::Recurrent(recurrent) = &mut model.layers[0] else { panic!("Not a recurrent layer") };
+
+        &mut recurrent.value_w
+    }
+
+    #[test]
+    fn windows_carry_the_state_and_cut_the_gradients() {
+        let mut model: Sequential<f64> = Sequential::with_seed(&[6, 2], 1)
+            .recurrent(Cell::Lstm, 3, true, Initializer::GlorotUniform)
+            .dense(1, Initializer::GlorotUniform);
+
+        let sequences: Vec<Sequence<f64>> = (0..2).map(|i| Sequence {
+            inputs: Array2::from_shape_fn((6, 2), |(t, j)| ((t + 2 * i + j) % 5) as f64 / 5.0 - 0.4),
+            outputs: Array2::from_shape_fn((6, 1), |(t, _)| ((t + i) % 2) as f64)
+        }).collect();
+
+        // windows of 4 then 2 steps, nothing is learned so that both see the same parameters
+        let mut workspace = Workspace::new(&model);
+        model.train_sequences(&mut workspace, &sequences, 0.0, 4).unwrap();
+
+        let (inputs, outputs) = sequence_batch(&sequences);
+        let (first, last) = (inputs.slice(s![.., ..4, ..]).to_owned().into_dyn(), inputs.slice(s![.., 4.., ..]).to_owned().into_dyn());
+        let outputs = outputs.slice(s![.., 4.., ..]).to_owned().into_dyn();
+
+        // the last window started from the state the first one left
+        let states = |model: &Sequential<f64>, inputs: &ArrayD<f64>| {
+            let mut states = vec![StateKind::Empty; model.layers.len()];
+            model.evaluate_states(vec![inputs.clone()], &mut states);
+
+            states
+        };
+
+        let StateKind::Recurrent(left) = &workspace.states[0] else { panic!("No state left") };
+        let StateKind::Recurrent(whole) = &states(&model, &inputs.clone().into_dyn())[0] else { panic!("No state left") };
+
+        for (left, whole) in left.iter().zip(whole) {
+            assert!(left.iter().zip(whole).all(|(a, b)| (a - b).abs() < 1e-12), "{left} != {whole}");
+        }
+
+        // loss of the last window, from the state of the first one with the parameters given to both or only to the last one
+        let loss = |model: &Sequential<f64>, carried: Vec<StateKind<f64>>| {
+            let mut scratch = Workspace::new(model);
+            scratch.states = carried;
+
+            model.forward_error(&mut scratch, vec![last.clone()], &outputs, &mut StdRng::seed_from_u64(0))
+        };
+
+        let carried = states(&model, &first);
+        let eps = 1e-6;
+        let mut cut = 0.0f64;
+        let mut through = 0.0f64;
+
+        for i in 0..weights(&mut model).len() {
+            let numerical = |model: &mut Sequential<f64>, through: bool| {
+                let at = |model: &mut Sequential<f64>, delta: f64| {
+                    *weights(model).iter_mut().nth(i).unwrap() += delta;
+                    let loss = loss(model, if through { states(model, &first) } else { carried.clone() });
+                    *weights(model).iter_mut().nth(i).unwrap() -= delta;
+
+                    loss
+                };
+
+                (at(model, eps) - at(model, -eps)) / (2.0 * eps)
+            };
+
+            let analytic = workspace.gradients[0][0].iter().nth(i).copied().unwrap();
+
+            cut = cut.max((analytic - numerical(&mut model, false)).abs());
+            through = through.max((analytic - numerical(&mut model, true)).abs());
+        }
+
+        assert!(cut < 1e-6, "Gradients of the last window reach past it: {cut}");
+        assert!(through > 1e-4, "The first window doesn't change the last one: {through}");
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Serialize, Deserialize};
+use rand::rngs::StdRng;
+use ndarray::{ArrayD, IxDyn};
+
+use super::{Layer, Mode, Float, standard};
+
+/// Changes the shape of every sample without touching its values
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reshape {
+    pub shape: Vec<usize>
+}
+
+impl Reshape {
+    pub fn new(shape: &[usize]) -> Self {
+        Self { shape: shape.to_vec() }
+    }
+
+    /// Every sample as one flat row
+    pub fn flatten(input: &[usize]) -> Self {
+        Self::new(&[input.iter().product()])
+    }
+}
+
+fn reshape<F: Float>(values: ArrayD<F>, shape: &[usize]) -> ArrayD<F> {
+    let mut dim = vec![values.shape()[0]];
+    dim.extend_from_slice(shape);
+
+    standard(values).into_shape(IxDyn(&dim)).expect("Can't reshape")
+}
+
+/// The cache is the shape of the input, for backward to give it back
+impl<F: Float> Layer<F> for Reshape {
+    type Cache = Vec<usize>;
+    type State = ();
+
+    fn output_shape(&self, _input: &[usize]) -> Vec<usize> {
+        self.shape.clone()
+    }
+
+    fn forward(&self, input: ArrayD<F>, cache: &mut Vec<usize>, _mode: Mode, _rng: &mut StdRng) -> ArrayD<F> {
+        *cache = input.shape().to_vec();
+
+        reshape(input, &self.shape)
+    }
+
+    fn evaluate(&self, input: ArrayD<F>) -> ArrayD<F> {
+        reshape(input, &self.shape)
+    }
+
+    fn backward(&self, error: ArrayD<F>, shape: &Vec<usize>, _gradients: &mut [ArrayD<F>]) -> ArrayD<F> {
+        reshape(error, &shape[1..])
+    }
+}
@@ -0,0 +1,59 @@
+use ndarray::ArrayD;
+
+use super::{Graph, Float};
+use super::sequential::{Layer, CacheKind, StateKind};
+
+/// Everything training writes to, kept apart from the parameters of the model
+/// so that inference never needs to mutate it
+#[derive(Debug, Clone)]
+pub struct Workspace<F: Float = f32> {
+    /// What every layer remembers of the batch for backpropagation
+    pub(super) caches: Vec<CacheKind<F>>,
+    /// Gradients summed over the batch, one per parameter of every layer
+    pub(super) gradients: Vec<Vec<ArrayD<F>>>,
+    /// States recurrent layers carry from one window of a sequence to the next
+    pub(super) states: Vec<StateKind<F>>
+}
+
+impl<F: Float> Workspace<F> {
+    pub fn new(graph: &Graph<F>) -> Self {
+        Self {
+            caches: vec![CacheKind::default(); graph.layers.len()],
+            gradients: graph.layers.iter().map(|layer| {
+                layer.parameters().iter().map(|parameter| ArrayD::zeros(parameter.raw_dim())).collect()
+            }).collect(),
+            states: vec![StateKind::default(); graph.layers.len()]
+        }
+    }
+
+    /// Adds the gradients of another workspace of the same model to this one
+    pub(super) fn add_gradient(&mut self, other: &Workspace<F>) {
+        for (gradient, other) in self.gradients.iter_mut().flatten().zip(other.gradients.iter().flatten()) {
+            *gradient += other;
+        }
+    }
+
+    /// First layer with a NaN or infinite gradient
+    pub(super) fn non_finite(&self) -> Option<usize> {
+        self.gradients.iter().position(|gradients| {
+            gradients.iter().flat_map(|gradient| gradient.iter()).any(|value| !value.is_finite())
+        })
+    }
+
+    /// Norm of all the summed gradients together
+    pub(super) fn norm(&self) -> F {
+        let squared: F = self.gradients.iter().flatten().map(|gradient| gradient.fold(F::zero(), |total, &value| total + value * value)).sum();
+
+        squared.sqrt()
+    }
+
+    pub(super) fn clear_gradient(&mut self) {
+        for gradient in self.gradients.iter_mut().flatten() {
+            gradient.fill(F::zero());
+        }
+    }
+
+    pub(super) fn clear_states(&mut self) {
+        self.states.fill(StateKind::default());
+    }
+}